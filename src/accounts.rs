@@ -0,0 +1,112 @@
+use crate::api;
+use crate::commands::Network;
+use crate::context::Context;
+use crate::storage;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+
+pub(crate) const STORAGE_NAME: &str = "account_links";
+
+/// A chat account as it's known to the network: the Discord user ID, or the services account an
+/// IRC user is logged in to. Nicknames aren't used, as anyone can take them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChatAccount {
+    pub(crate) network: Network,
+    pub(crate) account: String,
+}
+
+impl ChatAccount {
+    fn network_name(&self) -> &'static str {
+        match self.network {
+            Network::Discord => "discord",
+            Network::Irc => "irc",
+        }
+    }
+
+    fn key(&self) -> String {
+        format!("{}:{}", self.network_name(), self.account.to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LinkedAccount {
+    pub(crate) user_id: usize,
+    pub(crate) username: String,
+}
+
+/// dnbradio.com users keyed by `network:account`.
+pub(crate) type AccountLinks = HashMap<String, LinkedAccount>;
+
+pub(crate) fn get_linked_account(
+    context: &Context,
+    account: Option<&ChatAccount>,
+) -> Option<LinkedAccount> {
+    let account = account?;
+    context
+        .account_links
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&account.key())
+        .cloned()
+}
+
+pub(crate) async fn link(
+    context: &Context,
+    account: &ChatAccount,
+    code: &str,
+) -> Result<LinkedAccount> {
+    let response = api::link_account(
+        code.to_owned(),
+        account.network_name().to_owned(),
+        account.account.clone(),
+    )
+    .await?;
+    let (Some(user_id), Some(username)) = (response.user_id, response.username) else {
+        return Err(anyhow!(response.message));
+    };
+    let linked = LinkedAccount { user_id, username };
+    let mut account_links = context
+        .account_links
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    account_links.insert(account.key(), linked.clone());
+    storage::save(STORAGE_NAME, &*account_links);
+    Ok(linked)
+}
+
+pub(crate) fn unlink(context: &Context, account: &ChatAccount) -> Option<LinkedAccount> {
+    let mut account_links = context
+        .account_links
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    let removed = account_links.remove(&account.key());
+    storage::save(STORAGE_NAME, &*account_links);
+    removed
+}
+
+/// Tracks when unlinked users last rated or commented, as they can only be told apart by their
+/// nickname.
+#[derive(Default)]
+pub(crate) struct UnlinkedRateLimit {
+    last_used: HashMap<String, Instant>,
+}
+
+impl UnlinkedRateLimit {
+    /// Records a use by `nickname`, or returns how long they still have to wait.
+    pub(crate) fn check(&mut self, nickname: &str, interval: Duration) -> Result<(), Duration> {
+        let nickname = nickname.to_lowercase();
+        if let Some(last_used) = self.last_used.get(&nickname) {
+            let elapsed = last_used.elapsed();
+            if elapsed < interval {
+                return Err(interval - elapsed);
+            }
+        }
+        self.last_used
+            .retain(|_, last_used| last_used.elapsed() < interval);
+        self.last_used.insert(nickname, Instant::now());
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use crate::context::Context;
+use crate::formatting;
+use log::info;
+use std::env;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// How long to wait for more coloured lines before relaying what was collected.
+const FLUSH_DELAY: Duration = Duration::from_millis(1500);
+/// Discord's message length limit.
+const DISCORD_MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// Coloured lines collected from a single IRC user.
+struct PendingLines {
+    nickname: String,
+    avatar_url: Option<String>,
+    lines: Vec<String>,
+    last_line: Instant,
+}
+
+/// Collects consecutive coloured lines from IRC users, so multi-line ASCII art can be relayed to
+/// Discord as an ANSI code block instead of having its colours stripped.
+pub(crate) struct AnsiRelay {
+    pub(crate) enabled: bool,
+    min_lines: usize,
+    pending: Option<PendingLines>,
+}
+
+impl AnsiRelay {
+    pub(crate) fn from_env() -> Self {
+        let enabled = env::var("IRC_ANSI_COLOURS")
+            .unwrap_or("false".to_owned())
+            .parse()
+            .expect("IRC_ANSI_COLOURS must be true or false");
+        let min_lines = env::var("IRC_ANSI_MIN_LINES")
+            .unwrap_or("2".to_owned())
+            .parse()
+            .expect("IRC_ANSI_MIN_LINES must be a number");
+        AnsiRelay {
+            enabled,
+            min_lines,
+            pending: None,
+        }
+    }
+
+    /// Takes the pending lines if no more have come in for a while.
+    fn take_expired(&mut self) -> Option<PendingLines> {
+        let pending = self.pending.as_ref()?;
+        if pending.last_line.elapsed() < FLUSH_DELAY {
+            return None;
+        }
+        self.pending.take()
+    }
+
+    /// Takes the pending lines unless the next message continues them, which is only the case for
+    /// another coloured line from the same user.
+    fn take_interrupted(&mut self, coloured_line_from: Option<&str>) -> Option<PendingLines> {
+        let pending = self.pending.as_ref()?;
+        if coloured_line_from == Some(pending.nickname.as_str()) {
+            return self.take_expired();
+        }
+        self.pending.take()
+    }
+}
+
+/// Splits ANSI lines into as few code blocks as fit in a Discord message.
+fn code_blocks(lines: &[String]) -> Vec<String> {
+    const OPEN: &str = "```ansi\n";
+    const CLOSE: &str = "\n```";
+    let mut blocks = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut length = 0;
+    for line in lines {
+        let line_length = line.chars().count() + 1;
+        if !current.is_empty()
+            && OPEN.len() + length + line_length + CLOSE.len() > DISCORD_MAX_MESSAGE_LENGTH
+        {
+            blocks.push(format!("{OPEN}{}{CLOSE}", current.join("\n")));
+            current.clear();
+            length = 0;
+        }
+        current.push(line);
+        length += line_length;
+    }
+    if !current.is_empty() {
+        blocks.push(format!("{OPEN}{}{CLOSE}", current.join("\n")));
+    }
+    blocks
+}
+
+async fn send_pending(context: &Context, pending: PendingLines) {
+    if pending.lines.len()
+        < context
+            .ansi_relay
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .min_lines
+    {
+        for line in pending.lines {
+            context
+                .send_to_discord_webhook_relay(
+                    &pending.nickname,
+                    &line,
+                    pending.avatar_url.clone(),
+                    None,
+                )
+                .await;
+        }
+        return;
+    }
+
+    context.mark_someone_talked();
+    let lines = pending
+        .lines
+        .iter()
+        .map(|line| formatting::irc_to_ansi(line))
+        .collect::<Vec<_>>();
+    for block in code_blocks(&lines) {
+        context
+            .execute_discord_webhook(&pending.nickname, block, pending.avatar_url.clone())
+            .await;
+    }
+}
+
+/// Relays an IRC message to Discord, holding back coloured lines until it's clear whether they're
+/// part of a multi-line block.
+pub(crate) async fn relay(
+    context: &Context,
+    nickname: &str,
+    message: &str,
+    avatar_url: Option<String>,
+    message_id: Option<&str>,
+) {
+    let is_coloured = message.contains('\x03') && !message.starts_with("\x01ACTION ");
+    let (enabled, flushed) = {
+        let mut relay = context
+            .ansi_relay
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !relay.enabled {
+            (false, None)
+        } else {
+            let flushed = relay.take_interrupted(is_coloured.then_some(nickname));
+            if is_coloured {
+                let pending = relay.pending.get_or_insert_with(|| PendingLines {
+                    nickname: nickname.to_owned(),
+                    avatar_url: avatar_url.clone(),
+                    lines: Vec::new(),
+                    last_line: Instant::now(),
+                });
+                pending.lines.push(message.to_owned());
+                pending.last_line = Instant::now();
+            }
+            (true, flushed)
+        }
+    };
+    if let Some(flushed) = flushed {
+        send_pending(context, flushed).await;
+    }
+    if !enabled || !is_coloured {
+        context
+            .send_to_discord_webhook_relay(nickname, message, avatar_url, message_id)
+            .await;
+    }
+}
+
+/// Sends any held back lines right away, for shutting down.
+pub(crate) async fn flush(context: &Context) {
+    let pending = context
+        .ansi_relay
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .pending
+        .take();
+    if let Some(pending) = pending {
+        send_pending(context, pending).await;
+    }
+}
+
+pub(crate) async fn start(context: Context) {
+    if !context
+        .ansi_relay
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .enabled
+    {
+        return;
+    }
+
+    info!("Starting IRC ANSI colour relay loop");
+    context
+        .metrics
+        .watch("ansi_relay", Duration::from_millis(250));
+    loop {
+        sleep(Duration::from_millis(250)).await;
+        context.metrics.heartbeat("ansi_relay");
+        let pending = context
+            .ansi_relay
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take_expired();
+        if let Some(pending) = pending {
+            send_pending(&context, pending).await;
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use crate::context::Context;
+use crate::{events, graph, scheduler, topic};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use dyn_fmt::AsStrFormatExt;
 use serde::{Deserialize, Serialize};
+use serenity::all::ActivityData;
 use std::env;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
@@ -120,6 +122,21 @@ pub(crate) struct CommentsResponse {
     pub(crate) comments: Vec<Comment>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LinkAccount {
+    pub(crate) code: String,
+    pub(crate) network: String,
+    pub(crate) account: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LinkAccountResponse {
+    pub(crate) status: String,
+    pub(crate) message: String,
+    pub(crate) user_id: Option<usize>,
+    pub(crate) username: Option<String>,
+}
+
 pub(crate) async fn get_dnbradio_api_response<T>(path: &str) -> Result<T>
 where
     for<'de> T: Deserialize<'de>,
@@ -192,9 +209,12 @@ pub(crate) async fn get_now_playing() -> Result<NowPlayingResponse> {
     })
 }
 
+pub(crate) async fn get_schedule_entries() -> Result<Vec<ScheduleResponse>> {
+    get_azuracast_api_response::<Vec<ScheduleResponse>>("station/dnbradio/schedule").await
+}
+
 pub(crate) async fn get_schedule() -> Result<Vec<(DateTime<Utc>, DateTime<Utc>, String)>> {
-    let api_response =
-        get_azuracast_api_response::<Vec<ScheduleResponse>>("station/dnbradio/schedule").await?;
+    let api_response = get_schedule_entries().await?;
     Ok(api_response
         .into_iter()
         .map(|schedule| {
@@ -277,6 +297,25 @@ pub(crate) async fn get_comments(song_id: String) -> Result<CommentsResponse> {
     Ok(api_response)
 }
 
+/// Redeems a one-time code from the dnbradio.com account page, tying the chat account to the
+/// website user the code was issued to.
+pub(crate) async fn link_account(
+    code: String,
+    network: String,
+    account: String,
+) -> Result<LinkAccountResponse> {
+    let api_response = post_dnbradio_api_response::<LinkAccount, LinkAccountResponse>(
+        "account/link",
+        LinkAccount {
+            code,
+            network,
+            account,
+        },
+    )
+    .await?;
+    Ok(api_response)
+}
+
 pub(crate) async fn now_playing_loop(context: Context) {
     let now_playing_check_interval = env::var("NOW_PLAYING_CHECK_INTERVAL")
         .expect("NOW_PLAYING_CHECK_INTERVAL must be set")
@@ -292,8 +331,15 @@ pub(crate) async fn now_playing_loop(context: Context) {
 
     let mut last_time_sent = DateTime::from_timestamp(0, 0).unwrap();
     let mut last_track_id: Option<String> = None;
+    // The live DJ, and whether their show was announced.
+    let mut live_dj: Option<(String, bool)> = None;
+    let mut first_poll = true;
 
     log::info!("Starting now playing loop");
+    context.metrics.watch(
+        "now_playing",
+        Duration::from_secs(now_playing_check_interval),
+    );
     loop {
         log::debug!("Sleeping for {} seconds", now_playing_check_interval);
         sleep(Duration::from_secs(now_playing_check_interval)).await;
@@ -313,7 +359,45 @@ pub(crate) async fn now_playing_loop(context: Context) {
                     live,
                 } = now_playing_response;
 
+                context.metrics.heartbeat("now_playing");
+                context
+                    .metrics
+                    .listeners
+                    .store(listeners, Ordering::Relaxed);
                 let is_live = live.is_live;
+                let show_name = if live.streamer_name.is_empty() {
+                    &artist
+                } else {
+                    &live.streamer_name
+                };
+                if let Some(finished_show) =
+                    graph::record_listeners(&context, is_live, show_name, listeners)
+                {
+                    if let Some(channel) = &context.graph_discord_channel {
+                        graph::post_graph(&context, &finished_show, channel).await;
+                    }
+                }
+
+                match (is_live, &live_dj) {
+                    (true, None) => {
+                        // Don't announce a show that was already live before we started.
+                        let announced =
+                            !first_poll && scheduler::announce_live(&context, show_name).await;
+                        events::mark_active(&context).await;
+                        live_dj = Some((show_name.clone(), announced));
+                    }
+                    (false, Some((dj, announced))) => {
+                        // Only thank DJs whose show was announced.
+                        if *announced {
+                            scheduler::announce_show_over(&context, dj).await;
+                        }
+                        events::mark_completed(&context).await;
+                        live_dj = None;
+                    }
+                    _ => {}
+                }
+                first_poll = false;
+
                 context
                     .shazam_active
                     .store(is_live || duration > 1200, Ordering::Relaxed);
@@ -351,6 +435,16 @@ pub(crate) async fn now_playing_loop(context: Context) {
                     context.send_shazam(&track_id, false).await;
                 }
 
+                if track_changed {
+                    // Discord only shows streaming activities for Twitch and YouTube URLs.
+                    let activity = if is_live {
+                        ActivityData::listening(format!("{} LIVE", show_name))
+                    } else {
+                        ActivityData::listening(&track_id)
+                    };
+                    context.set_discord_activity(activity).await;
+                }
+
                 if send_message {
                     log::debug!("Sending now playing message: {}", now_playing_string);
                     last_time_sent = chrono::Utc::now();
@@ -361,26 +455,26 @@ pub(crate) async fn now_playing_loop(context: Context) {
                             is_live,
                         )
                         .await;
-                    _ = context
-                        .set_irc_topic(if is_live {
-                            let topic_artist = if !live.streamer_name.is_empty()
-                                && !artist
-                                    .to_lowercase()
-                                    .contains(&live.streamer_name.to_lowercase())
-                            {
-                                format!("{} - {}", live.streamer_name, artist)
-                            } else {
-                                artist.clone()
-                            };
-                            irc_live_topic.format(&[topic_artist, title])
+                    let topic = if is_live {
+                        let topic_artist = if !live.streamer_name.is_empty()
+                            && !artist
+                                .to_lowercase()
+                                .contains(&live.streamer_name.to_lowercase())
+                        {
+                            format!("{} - {}", live.streamer_name, artist)
                         } else {
-                            irc_default_topic.clone()
-                        })
-                        .await;
+                            artist.clone()
+                        };
+                        irc_live_topic.format(&[topic_artist, title])
+                    } else {
+                        irc_default_topic.clone()
+                    };
+                    topic::set_topic(&context, topic, track_changed).await;
                 }
             }
             Err(e) => {
                 log::error!("Error getting now playing: {e}");
+                context.metrics.api_errors.fetch_add(1, Ordering::Relaxed);
             }
         }
     }
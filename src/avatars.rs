@@ -0,0 +1,125 @@
+use crate::context::Context;
+use crate::storage;
+use log::info;
+use serenity::all::UserId;
+use std::collections::HashMap;
+use std::env;
+use std::sync::PoisonError;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub(crate) const STORAGE_NAME: &str = "nick_links";
+
+/// Discord user IDs keyed by lowercase IRC nickname, for IRC users whose nick doesn't match their
+/// Discord name.
+pub(crate) type NickLinks = HashMap<String, u64>;
+
+/// Avatars of the members of the bridged guild, so relaying an IRC line doesn't have to go through
+/// the member list.
+#[derive(Default)]
+pub(crate) struct AvatarIndex {
+    by_name: HashMap<String, String>,
+    by_user: HashMap<UserId, String>,
+}
+
+/// Rebuilds the avatar index from the cache. Returns false if the guild isn't cached yet.
+fn refresh(context: &Context) -> bool {
+    let Some(guild) = context
+        .discord_cache
+        .guilds()
+        .into_iter()
+        .filter_map(|guild_id| context.discord_cache.guild(guild_id))
+        .find(|guild| guild.channels.contains_key(&context.discord_channel))
+    else {
+        return false;
+    };
+    let mut index = AvatarIndex::default();
+    for member in guild.members.values().filter(|member| !member.user.bot) {
+        let face = member.face();
+        index
+            .by_name
+            .insert(member.display_name().to_lowercase(), face.clone());
+        index.by_user.insert(member.user.id, face);
+    }
+    drop(guild);
+    *context
+        .avatars
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = index;
+    true
+}
+
+/// A stable hash of a nickname to seed its identicon with. This is FNV-1a, which unlike the
+/// standard library hasher is guaranteed not to change between releases.
+fn nickname_seed(nickname: &str) -> String {
+    let hash = nickname
+        .to_lowercase()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{hash:016x}")
+}
+
+/// Returns the avatar to relay an IRC user's messages with: the avatar of the Discord user their
+/// nick is linked to or matches, or else a generated identicon.
+pub(crate) fn get_avatar_url(context: &Context, nickname: &str) -> Option<String> {
+    let nickname = nickname.to_lowercase();
+    let linked_user = context
+        .nick_links
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&nickname)
+        .copied();
+    {
+        let avatars = context
+            .avatars
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let avatar = match linked_user {
+            Some(user_id) => avatars.by_user.get(&UserId::new(user_id)),
+            None => avatars.by_name.get(&nickname),
+        };
+        if let Some(avatar) = avatar {
+            return Some(avatar.clone());
+        }
+    }
+    context
+        .identicon_url
+        .as_ref()
+        .map(|template| template.replace("{}", &nickname_seed(&nickname)))
+}
+
+/// Links an IRC nick to a Discord user, or removes the link if `user_id` is `None`.
+pub(crate) fn set_nick_link(context: &Context, nickname: &str, user_id: Option<UserId>) {
+    let mut nick_links = context
+        .nick_links
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    match user_id {
+        Some(user_id) => nick_links.insert(nickname.to_lowercase(), user_id.get()),
+        None => nick_links.remove(&nickname.to_lowercase()),
+    };
+    storage::save(STORAGE_NAME, &*nick_links);
+}
+
+pub(crate) async fn start(context: Context) {
+    let interval = Duration::from_secs(
+        env::var("AVATAR_REFRESH_INTERVAL")
+            .unwrap_or("300".to_owned())
+            .parse()
+            .expect("AVATAR_REFRESH_INTERVAL must be a number"),
+    );
+
+    info!("Starting avatar index refresh loop");
+    context.metrics.watch("avatars", interval);
+    loop {
+        if refresh(&context) {
+            context.metrics.heartbeat("avatars");
+            sleep(interval).await;
+        } else {
+            // The cache fills up shortly after connecting to Discord.
+            sleep(Duration::from_secs(10)).await;
+        }
+    }
+}
@@ -1,16 +1,53 @@
 use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
 
+use crate::accounts::ChatAccount;
 use crate::context::Context;
-use crate::{api, shazam};
+use crate::{accounts, api, avatars, discord, graph, lag, shazam, timezones};
 use anyhow::Result;
+use chrono::{DateTime, Datelike, Days, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
 use log::{error, warn};
+use serenity::all::UserId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Network {
+    Discord,
+    Irc,
+}
 
 pub(crate) async fn handle_command(
     context: &Context,
+    network: Network,
     channel: &str,
     nickname: &str,
     command: &str,
-    _is_admin: bool,
+    is_admin: bool,
+    account: Option<ChatAccount>,
+) -> Result<()> {
+    if context.shutting_down.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    context.metrics.commands.fetch_add(1, Ordering::Relaxed);
+    let result = run_command(
+        context, network, channel, nickname, command, is_admin, account,
+    )
+    .await;
+    // Commands only fail when the API does.
+    if result.is_err() {
+        context.metrics.api_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+async fn run_command(
+    context: &Context,
+    network: Network,
+    channel: &str,
+    nickname: &str,
+    command: &str,
+    is_admin: bool,
+    account: Option<ChatAccount>,
 ) -> Result<()> {
     let mut command_parts = command.split(' ');
     let command_name = command_parts.next().unwrap_or("");
@@ -23,13 +60,23 @@ pub(crate) async fn handle_command(
         "id" => id(context, nickname).await?,
         "submit" => context.send_message("If you're interested in becoming a DJ on the station, please email submissions@dnbradio.com!").await,
         "ratings" => ratings(context).await?,
-        "rate" => rate(channel, nickname, context, command_args).await?,
+        "rate" => rate(channel, nickname, account.as_ref(), context, command_args).await?,
         "comments" => comments(context).await?,
-        "comment" => comment(channel, nickname, context, command_args).await?,
+        "comment" => comment(channel, nickname, account.as_ref(), context, command_args).await?,
+        "unlink" => unlink(context, nickname, account.as_ref()).await,
         "boh" | "bohboh" | "bohbohboh" => boh(context, command_name.matches("boh").count(), false).await?,
         "hob" | "hobhob" | "hobhobhob" => boh(context, command_name.matches("hob").count(), true).await?,
-        "sched" | "schedule" => schedule(context).await?,
+        "sched" | "schedule" => {
+            schedule(context, nickname, account.as_ref(), command_args).await?
+        }
+        "next" => next(context, nickname, account.as_ref()).await?,
+        "tz" => tz(context, network, nickname, account.as_ref(), command_args).await,
         "queue" => queue(context).await?,
+        "graph" => graph(context, command_args).await,
+        "who" | "names" => who(context, network).await,
+        "lag" => lag(context).await,
+        "linknick" if is_admin => link_nick(context, command_args).await,
+        "unlinknick" if is_admin => unlink_nick(context, command_args).await,
         "incoming" => context.send_action(&format!("grabs {} and runs yelling INCOMING!", nickname)).await,
         _ => {
             warn!(
@@ -41,6 +88,133 @@ pub(crate) async fn handle_command(
     Ok(())
 }
 
+/// Joins names into a list that fits within `max_length` bytes, noting how many were left out.
+fn format_name_list(names: &[String], max_length: usize) -> String {
+    let mut list = String::new();
+    for (index, name) in names.iter().enumerate() {
+        let remaining = names.len() - index;
+        let more = format!(" and {} more", remaining);
+        if list.len() + name.len() + 2 + more.len() > max_length {
+            list.push_str(if list.is_empty() {
+                more.trim_start()
+            } else {
+                &more
+            });
+            break;
+        }
+        if !list.is_empty() {
+            list.push_str(", ");
+        }
+        list.push_str(name);
+    }
+    list
+}
+
+async fn who(context: &Context, network: Network) {
+    match network {
+        Network::Discord => {
+            let mut names = context
+                .irc_names
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>();
+            names.sort_by_key(|name| name.to_lowercase());
+            let names = names
+                .iter()
+                .map(|name| Context::escape_discord_markdown(name))
+                .collect::<Vec<_>>();
+            context
+                .send_to_discord_channel(
+                    &format!(
+                        "On IRC in {} ({}): {}",
+                        context.irc_channel,
+                        names.len(),
+                        format_name_list(&names, 1800)
+                    ),
+                    &context.discord_channel,
+                )
+                .await;
+        }
+        Network::Irc => {
+            let names = discord::get_online_members(context);
+            context
+                .send_to_irc(
+                    &format!(
+                        "Online on Discord ({}): {}",
+                        names.len(),
+                        format_name_list(&names, 350)
+                    ),
+                    None,
+                )
+                .await;
+        }
+    }
+}
+
+async fn lag(context: &Context) {
+    let discord_latency = lag::discord_latency(context).await;
+    context
+        .send_message(&format!(
+            "IRC lag: {} - Discord gateway latency: {}",
+            lag::format_lag(lag::irc_lag(context)),
+            lag::format_lag(discord_latency)
+        ))
+        .await;
+}
+
+/// Parses a Discord user ID, given either as a number or as a mention.
+fn parse_user_id(text: &str) -> Option<UserId> {
+    let id = text
+        .trim_start_matches("<@")
+        .trim_start_matches('!')
+        .trim_end_matches('>');
+    id.parse().ok().filter(|id| *id != 0).map(UserId::new)
+}
+
+async fn link_nick(context: &Context, args: Vec<&str>) {
+    let message = match (
+        args.first(),
+        args.get(1).and_then(|user| parse_user_id(user)),
+    ) {
+        (Some(nickname), Some(user_id)) => {
+            avatars::set_nick_link(context, nickname, Some(user_id));
+            format!("Linked IRC nick {} to Discord user {}", nickname, user_id)
+        }
+        _ => format!(
+            "Usage: {}linknick <irc nick> <discord user id or mention>",
+            context.command_prefix
+        ),
+    };
+    context.send_message(&message).await;
+}
+
+async fn unlink_nick(context: &Context, args: Vec<&str>) {
+    let message = match args.first() {
+        Some(nickname) => {
+            avatars::set_nick_link(context, nickname, None);
+            format!("Unlinked IRC nick {}", nickname)
+        }
+        None => format!("Usage: {}unlinknick <irc nick>", context.command_prefix),
+    };
+    context.send_message(&message).await;
+}
+
+async fn graph(context: &Context, args: Vec<&str>) {
+    let show = if args.is_empty() {
+        None
+    } else {
+        Some(args.join(" "))
+    };
+    let Some(listeners) = graph::find_show(context, show.as_deref()) else {
+        context.send_message("No listener graph available").await;
+        return;
+    };
+    graph::post_graph(context, &listeners, &context.discord_channel).await;
+    context.send_to_irc(&listeners.summary(), None).await;
+}
+
 async fn queue(context: &Context) -> Result<()> {
     match api::get_queue().await {
         Ok(queue) => {
@@ -184,32 +358,200 @@ async fn boh(context: &Context, factor: usize, reverse: bool) -> Result<()> {
     Ok(())
 }
 
-async fn schedule(context: &Context) -> Result<()> {
-    let schedule = api::get_schedule().await?;
+/// Who a timezone is stored under: Discord users by their user ID, as display names aren't unique,
+/// and IRC users by their services account, as anyone can take a nickname. IRC users who aren't
+/// logged in fall back to their nickname.
+fn timezone_user(nickname: &str, account: Option<&ChatAccount>) -> String {
+    match account {
+        Some(ChatAccount {
+            network: Network::Discord,
+            account,
+        }) => format!("discord:{account}"),
+        Some(ChatAccount {
+            network: Network::Irc,
+            account,
+        }) => format!("irc:{account}"),
+        None => nickname.to_owned(),
+    }
+}
+
+fn get_user_timezone(
+    context: &Context,
+    nickname: &str,
+    account: Option<&ChatAccount>,
+) -> Option<Tz> {
+    timezones::get_timezone(context, &timezone_user(nickname, account))
+}
+
+fn format_relative_time(start: DateTime<Utc>) -> String {
+    let time_difference = (Utc::now() - start).num_minutes();
+    let time_difference_string = if time_difference > -60 {
+        format!("{}m", time_difference.abs())
+    } else {
+        format!("{:.1}h", time_difference.abs() as f32 / 60.0)
+    };
+    if time_difference > 0 {
+        format!("Started {} ago", time_difference_string)
+    } else {
+        format!("Starts in {}", time_difference_string)
+    }
+}
+
+fn format_local_time(start: DateTime<Utc>, end: DateTime<Utc>, timezone: Tz) -> String {
+    let start = start.with_timezone(&timezone);
+    let end = end.with_timezone(&timezone);
+    format!(
+        "{}-{} {}",
+        start.format("%a %H:%M"),
+        end.format("%H:%M"),
+        start.format("%Z")
+    )
+}
+
+/// Parses `today`, `tomorrow` or a weekday into the next matching date in the given timezone.
+fn parse_day(day: &str, timezone: Tz) -> Option<NaiveDate> {
+    let today = Utc::now().with_timezone(&timezone).date_naive();
+    match day.to_lowercase().as_str() {
+        "today" => Some(today),
+        "tomorrow" => today.succ_opt(),
+        day => {
+            let weekday = day.parse::<Weekday>().ok()?;
+            let days_ahead =
+                (7 + weekday.num_days_from_monday() - today.weekday().num_days_from_monday()) % 7;
+            today.checked_add_days(Days::new(days_ahead as u64))
+        }
+    }
+}
+
+async fn schedule(
+    context: &Context,
+    nickname: &str,
+    account: Option<&ChatAccount>,
+    args: Vec<&str>,
+) -> Result<()> {
+    let timezone = get_user_timezone(context, nickname, account);
+    let day = match args.first() {
+        Some(day) => match parse_day(day, timezone.unwrap_or(Tz::UTC)) {
+            Some(day) => Some(day),
+            None => {
+                context
+                    .send_message(&format!(
+                        "Usage: {}schedule [today|tomorrow|<weekday>]",
+                        context.command_prefix
+                    ))
+                    .await;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let mut schedule = api::get_schedule().await?;
+    if let Some(day) = day {
+        let day_timezone = timezone.unwrap_or(Tz::UTC);
+        schedule.retain(|(start, _, _)| start.with_timezone(&day_timezone).date_naive() == day);
+        if schedule.is_empty() {
+            context
+                .send_message(&format!("Nothing scheduled on {}", day.format("%A %-d %B")))
+                .await;
+            return Ok(());
+        }
+    }
+
     let mut irc_string = String::new();
     let mut discord_string = String::new();
-    for (start, _, title) in schedule {
-        let time_difference = (chrono::Utc::now() - start).num_minutes();
-        let mut time_difference_string = if time_difference > -60 {
-            format!("{}m", time_difference.abs())
-        } else {
-            format!("{:.1}h", time_difference.abs() as f32 / 60.0)
+    for (start, end, title) in schedule {
+        // Show absolute times if the user picked a timezone or asked for a specific day.
+        let time_string = match (timezone, day) {
+            (Some(timezone), _) => format_local_time(start, end, timezone),
+            (None, Some(_)) => format_local_time(start, end, Tz::UTC),
+            (None, None) => format_relative_time(start),
         };
-        if time_difference > 0 {
-            time_difference_string = format!("Started {} ago", time_difference_string);
-        } else {
-            time_difference_string = format!("Starts in {}", time_difference_string);
-        }
-        irc_string.push_str(&format!("{}: {}\n", time_difference_string, title));
-        discord_string.push_str(&format!("<t:{}:t> (<t:{}:R>): {}\n", start.timestamp(), start.timestamp(), title));
+        irc_string.push_str(&format!("{}: {}\n", time_string, title));
+        discord_string.push_str(&format!(
+            "<t:{}:t> (<t:{}:R>): {}\n",
+            start.timestamp(),
+            start.timestamp(),
+            title
+        ));
     }
-    irc_string.push_str("For additional info check https://dnbradio.com/player/stations/1/schedule/");
+    irc_string
+        .push_str("For additional info check https://dnbradio.com/player/stations/1/schedule/");
     discord_string.push_str("For additional info check [the full schedule on our website](<https://dnbradio.com/player/stations/1/schedule/>)");
     context.send_to_irc(&irc_string, None).await;
     context.send_to_discord(&discord_string).await;
     Ok(())
 }
 
+async fn next(context: &Context, nickname: &str, account: Option<&ChatAccount>) -> Result<()> {
+    let now = Utc::now();
+    let next_show = api::get_schedule()
+        .await?
+        .into_iter()
+        .filter(|(start, _, _)| *start > now)
+        .min_by_key(|(start, _, _)| *start);
+    let Some((start, end, title)) = next_show else {
+        context.send_message("Nothing scheduled").await;
+        return Ok(());
+    };
+    let irc_time = match get_user_timezone(context, nickname, account) {
+        Some(timezone) => format!(
+            "{} ({})",
+            format_local_time(start, end, timezone),
+            format_relative_time(start)
+        ),
+        None => format_relative_time(start),
+    };
+    context
+        .send_to_irc(&format!("Next up: {} - {}", title, irc_time), None)
+        .await;
+    context
+        .send_to_discord(&format!(
+            "Next up: {} - <t:{}:F> (<t:{}:R>)",
+            title,
+            start.timestamp(),
+            start.timestamp()
+        ))
+        .await;
+    Ok(())
+}
+
+async fn tz(
+    context: &Context,
+    network: Network,
+    nickname: &str,
+    account: Option<&ChatAccount>,
+    args: Vec<&str>,
+) {
+    let user = timezone_user(nickname, account);
+    let message = match args.first() {
+        None => match timezones::get_timezone(context, &user) {
+            Some(timezone) => format!("{}: your timezone is {}", nickname, timezone),
+            None => format!(
+                "Usage: {}tz <timezone> (e.g. Europe/London) or {}tz clear",
+                context.command_prefix, context.command_prefix
+            ),
+        },
+        Some(&"clear") => {
+            timezones::set_timezone(context, &user, None);
+            format!("{}: timezone cleared", nickname)
+        }
+        Some(name) => match Tz::from_str_insensitive(name) {
+            Ok(timezone) => {
+                timezones::set_timezone(context, &user, Some(timezone));
+                format!("{}: timezone set to {}", nickname, timezone)
+            }
+            Err(_) => format!("Unknown timezone {}, use a name like Europe/London", name),
+        },
+    };
+    match network {
+        Network::Irc => context.send_to_irc(&message, None).await,
+        // Discord shows times in everyone's own timezone already, the one set here only picks
+        // which day `schedule` shows.
+        Network::Discord => context.send_to_discord(&message).await,
+    }
+}
+
 async fn ratings(context: &Context) -> Result<()> {
     let now_playing_response = api::get_now_playing().await?;
     let song = now_playing_response.now_playing.song;
@@ -233,7 +575,108 @@ async fn ratings(context: &Context) -> Result<()> {
     Ok(())
 }
 
-async fn rate(channel: &str, nickname: &str, context: &Context, args: Vec<&str>) -> Result<()> {
+/// Returns the dnbradio.com user ID to rate or comment as, which is 0 for users who haven't linked
+/// their account. Those are rate limited, and `None` is returned if they have to wait.
+async fn get_user_id(
+    context: &Context,
+    nickname: &str,
+    account: Option<&ChatAccount>,
+) -> Option<usize> {
+    if let Some(linked) = accounts::get_linked_account(context, account) {
+        return Some(linked.user_id);
+    }
+    let result = context
+        .unlinked_rate_limit
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .check(nickname, context.unlinked_rate_limit_interval);
+    match result {
+        Ok(()) => Some(0),
+        Err(wait) => {
+            context
+                .send_message(&format!(
+                    "{}: please wait {}s, or link your dnbradio.com account with {}link to lift the limit",
+                    nickname,
+                    wait.as_secs() + 1,
+                    context.command_prefix
+                ))
+                .await;
+            None
+        }
+    }
+}
+
+/// Whether a message is the `link` command. Its one-time code must stay private, so it's never
+/// relayed and is handled by [`handle_link`] rather than [`handle_command`].
+pub(crate) fn is_link_command(context: &Context, message: &str) -> bool {
+    message
+        .strip_prefix(&context.command_prefix)
+        .is_some_and(|command| command.split(' ').next() == Some("link"))
+}
+
+/// Handles `link`, returning the reply for the sender only. Codes are only accepted in private
+/// messages, as anyone watching the channel could redeem them first.
+pub(crate) async fn handle_link(
+    context: &Context,
+    nickname: &str,
+    account: Option<ChatAccount>,
+    command: &str,
+    private: bool,
+) -> String {
+    context.metrics.commands.fetch_add(1, Ordering::Relaxed);
+    let code = command.split(' ').nth(1).filter(|code| !code.is_empty());
+    let Some(account) = account else {
+        return format!(
+            "{}: log in to services (NickServ) first, so there's an account to link",
+            nickname
+        );
+    };
+    match code {
+        Some(_) if !private => format!(
+            "{}: link codes only work in a private message, as anyone here could use them. Get a new code at {} and send me {}link <code> privately",
+            nickname, context.account_link_url, context.command_prefix
+        ),
+        None => match accounts::get_linked_account(context, Some(&account)) {
+            Some(linked) => format!(
+                "{}: linked to dnbradio.com user {}",
+                nickname, linked.username
+            ),
+            None => format!(
+                "{}: get a code at {} and send me {}link <code> in a private message",
+                nickname, context.account_link_url, context.command_prefix
+            ),
+        },
+        Some(code) => match accounts::link(context, &account, code).await {
+            Ok(linked) => format!(
+                "{}: linked to dnbradio.com user {}",
+                nickname, linked.username
+            ),
+            Err(error) => {
+                warn!("Error linking account for {}: {:?}", nickname, error);
+                format!("{}: could not link your account: {}", nickname, error)
+            }
+        },
+    }
+}
+
+async fn unlink(context: &Context, nickname: &str, account: Option<&ChatAccount>) {
+    let message = match account.and_then(|account| accounts::unlink(context, account)) {
+        Some(linked) => format!(
+            "{}: unlinked from dnbradio.com user {}",
+            nickname, linked.username
+        ),
+        None => format!("{}: your account isn't linked", nickname),
+    };
+    context.send_message(&message).await;
+}
+
+async fn rate(
+    channel: &str,
+    nickname: &str,
+    account: Option<&ChatAccount>,
+    context: &Context,
+    args: Vec<&str>,
+) -> Result<()> {
     if args.is_empty() {
         context
             .send_message(&format!(
@@ -253,13 +696,16 @@ async fn rate(channel: &str, nickname: &str, context: &Context, args: Vec<&str>)
             .await;
         return Ok(());
     }
+    let Some(user_id) = get_user_id(context, nickname, account).await else {
+        return Ok(());
+    };
     let now_playing_response = api::get_now_playing().await?;
     let is_live = now_playing_response.live.is_live;
 
     let rate_response = api::set_rating(
         now_playing_response.now_playing.song.id,
         if is_live { 'L' } else { 'S' },
-        0,
+        user_id,
         rating,
         channel.to_owned(),
         nickname.to_owned(),
@@ -309,7 +755,13 @@ async fn comments(context: &Context) -> Result<()> {
     Ok(())
 }
 
-async fn comment(channel: &str, nickname: &str, context: &Context, args: Vec<&str>) -> Result<()> {
+async fn comment(
+    channel: &str,
+    nickname: &str,
+    account: Option<&ChatAccount>,
+    context: &Context,
+    args: Vec<&str>,
+) -> Result<()> {
     if args.is_empty() {
         context
             .send_message(&format!(
@@ -319,6 +771,9 @@ async fn comment(channel: &str, nickname: &str, context: &Context, args: Vec<&st
             .await;
         return Ok(());
     }
+    let Some(user_id) = get_user_id(context, nickname, account).await else {
+        return Ok(());
+    };
     let now_playing_response = api::get_now_playing().await?;
     let is_live = now_playing_response.live.is_live;
 
@@ -327,7 +782,7 @@ async fn comment(channel: &str, nickname: &str, context: &Context, args: Vec<&st
     api::add_comment(
         now_playing_response.now_playing.song.id,
         if is_live { 'L' } else { 'S' },
-        0,
+        user_id,
         channel.to_owned(),
         nickname.to_owned(),
         comment.clone(),
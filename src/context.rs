@@ -1,24 +1,70 @@
+use crate::accounts::{AccountLinks, UnlinkedRateLimit};
+use crate::ansi_relay::AnsiRelay;
+use crate::avatars::{AvatarIndex, NickLinks};
+use crate::discord::RelayedMessage;
+use crate::events::SyncedEvents;
+use crate::formatting;
+use crate::graph::ListenerGraphs;
+use crate::irc::IrcAccounts;
+use crate::irc_caps::SaslMechanism;
+use crate::irc_link::IrcLink;
+use crate::irc_nick::NickRegain;
+use crate::irc_perform::PerformScript;
+use crate::irc_split;
+use crate::lag::IrcLag;
+use crate::mentions::UnknownMentions;
+use crate::metrics::Metrics;
+use crate::presence::PresenceRelay;
+use crate::storage;
+use crate::supervisor::RestartPolicy;
+use crate::timezones::Timezones;
+use crate::topic::TopicState;
 use anyhow::Result;
 use chrono::NaiveDateTime;
 use irc::client::Sender;
-use irc::proto::Command;
-use log::error;
-use regex::Regex;
-use serenity::all::{Cache, ChannelId, EditMessage, ExecuteWebhook, Http, MessageId, Webhook};
-use std::collections::VecDeque;
+use irc::proto::message::Tag;
+use irc::proto::{Command, Message};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    ActivityData, Cache, ChannelId, CreateAttachment, CreateMessage, EditMessage, ExecuteWebhook,
+    GetMessages, Http, MessageId, RoleId, ShardManager, Webhook,
+};
+use std::collections::{HashSet, VecDeque};
+use std::sync::PoisonError;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc, Mutex, RwLock,
 };
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct Context {
     pub(crate) discord_http: Arc<Http>,
     pub(crate) discord_cache: Arc<Cache>,
+    pub(crate) discord_shard_manager: Arc<ShardManager>,
     pub(crate) discord_channel: ChannelId,
     pub(crate) discord_webhook_url: String,
+    pub(crate) discord_relayed: Arc<Mutex<VecDeque<RelayedMessage>>>,
+    pub(crate) unknown_mentions: Arc<Mutex<UnknownMentions>>,
     pub(crate) irc_sender: Arc<RwLock<Sender>>,
     pub(crate) irc_channel: String,
+    pub(crate) irc_names: Arc<RwLock<HashSet<String>>>,
+    pub(crate) irc_presence: Arc<Mutex<PresenceRelay>>,
+    pub(crate) irc_admins: Vec<String>,
+    pub(crate) irc_sasl: Option<SaslMechanism>,
+    pub(crate) irc_caps: Arc<RwLock<HashSet<String>>>,
+    pub(crate) irc_accounts: Arc<RwLock<IrcAccounts>>,
+    pub(crate) irc_message_ids: Arc<Mutex<VecDeque<(MessageId, String)>>>,
+    pub(crate) irc_nickname: Arc<RwLock<String>>,
+    pub(crate) irc_link: Arc<Mutex<IrcLink>>,
+    pub(crate) irc_lag: Arc<Mutex<IrcLag>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) restart_policy: Arc<RestartPolicy>,
+    pub(crate) shutting_down: Arc<AtomicBool>,
+    pub(crate) irc_nick_regain: NickRegain,
+    pub(crate) irc_perform: Arc<PerformScript>,
+    pub(crate) ansi_relay: Arc<Mutex<AnsiRelay>>,
     pub(crate) command_prefix: String,
     pub(crate) last_track: Arc<RwLock<Option<(NaiveDateTime, String)>>>,
     pub(crate) shazam_discord_channel: ChannelId,
@@ -27,14 +73,41 @@ pub struct Context {
     pub(crate) shazam_active: Arc<AtomicBool>,
     pub(crate) np_state: Arc<Mutex<NpState>>,
     pub(crate) np_someone_talked: Arc<AtomicBool>,
+    pub(crate) listener_graphs: Arc<Mutex<ListenerGraphs>>,
+    pub(crate) graph_discord_channel: Option<ChannelId>,
+    pub(crate) discord_live_role: Option<RoleId>,
+    pub(crate) stream_url: String,
+    pub(crate) scheduled_events: Option<Arc<tokio::sync::Mutex<SyncedEvents>>>,
+    pub(crate) timezones: Arc<RwLock<Timezones>>,
+    pub(crate) topic_state: Arc<Mutex<TopicState>>,
+    pub(crate) discord_topic_sync: bool,
+    pub(crate) paste_url: Option<String>,
+    pub(crate) paste_threshold: usize,
+    pub(crate) avatars: Arc<RwLock<AvatarIndex>>,
+    pub(crate) nick_links: Arc<RwLock<NickLinks>>,
+    pub(crate) identicon_url: Option<String>,
+    pub(crate) account_links: Arc<RwLock<AccountLinks>>,
+    pub(crate) account_link_url: String,
+    pub(crate) unlinked_rate_limit: Arc<Mutex<UnlinkedRateLimit>>,
+    pub(crate) unlinked_rate_limit_interval: Duration,
 }
 
+pub(crate) const NP_STATE_STORAGE_NAME: &str = "np_state";
+pub(crate) const NP_SOMEONE_TALKED_STORAGE_NAME: &str = "np_someone_talked";
+
+#[derive(Default, Serialize, Deserialize)]
 pub(crate) struct NpState {
     pub(crate) message_id: Option<MessageId>,
     pub(crate) lines: VecDeque<String>,
 }
 
 impl Context {
+    pub(crate) async fn set_discord_activity(&self, activity: ActivityData) {
+        for runner in self.discord_shard_manager.runners.lock().await.values() {
+            runner.runner_tx.set_activity(Some(activity.clone()));
+        }
+    }
+
     pub(crate) async fn send_to_discord(&self, message: &str) {
         self.send_to_discord_channel(&message.replace('|', "\\|"), &self.discord_channel)
             .await;
@@ -46,27 +119,23 @@ impl Context {
         }
     }
 
+    pub(crate) async fn send_file_to_discord_channel(
+        &self,
+        message: &str,
+        filename: &str,
+        data: Vec<u8>,
+        channel: &ChannelId,
+    ) {
+        let builder = CreateMessage::new()
+            .content(message)
+            .add_file(CreateAttachment::bytes(data, filename));
+        if let Err(error) = channel.send_message(&self.discord_http, builder).await {
+            error!("Error sending file to Discord: {:?}", error);
+        }
+    }
+
     pub(crate) fn discord_markdown_to_irc(&self, message: &str) -> String {
-        // Custom emoji: <:name:id> or <a:name:id> → :name:
-        let re = Regex::new(r"<a?:(\w+):\d+>").unwrap();
-        let message = re.replace_all(message, ":$1:").to_string();
-        // Bold+italic: ***text*** → \x02\x1Dtext\x1D\x02
-        let re = Regex::new(r"\*\*\*(.*?)\*\*\*").unwrap();
-        let message = re.replace_all(&message, "\x02\x1D$1\x1D\x02").to_string();
-        // Bold: **text** → \x02text\x02
-        let re = Regex::new(r"\*\*(.*?)\*\*").unwrap();
-        let message = re.replace_all(&message, "\x02$1\x02").to_string();
-        // Strikethrough: ~~text~~ → \x1Etext\x1E
-        let re = Regex::new(r"~~(.*?)~~").unwrap();
-        let message = re.replace_all(&message, "\x1E$1\x1E").to_string();
-        // Underline: __text__ → \x1Ftext\x1F (must come before single _)
-        let re = Regex::new(r"__(.*?)__").unwrap();
-        let message = re.replace_all(&message, "\x1F$1\x1F").to_string();
-        // Italic: *text* or _text_ → \x1Dtext\x1D
-        let re = Regex::new(r"\*(.*?)\*").unwrap();
-        let message = re.replace_all(&message, "\x1D$1\x1D").to_string();
-        let re = Regex::new(r"_(.*?)_").unwrap();
-        re.replace_all(&message, "\x1D$1\x1D").to_string()
+        formatting::discord_to_irc(message)
     }
 
     pub(crate) fn escape_discord_markdown(text: &str) -> String {
@@ -75,46 +144,46 @@ impl Context {
             .replace('_', "\\_")
             .replace('*', "\\*")
             .replace('~', "\\~")
+            .replace('`', "\\`")
             .replace('|', "\\|")
     }
 
-    pub(crate) fn translate_control_character(
-        &self,
-        character: u32,
-        replacement: &str,
-        message: &str,
-    ) -> String {
-        let regex = Regex::new(&format!(r"\x{:02X}(.*?)\x{:02X}", character, character)).unwrap();
-        let message = regex.replace_all(message, |caps: &regex::Captures| {
-            format!(
-                "{}{}{}",
-                replacement,
-                Self::escape_discord_markdown(&caps[1]),
-                replacement
-            )
-        });
-        let regex = Regex::new(&format!(r"\x{:02X}(.*)", character)).unwrap();
-        regex
-            .replace_all(&message, |caps: &regex::Captures| {
-                format!(
-                    "{}{}{}",
-                    replacement,
-                    Self::escape_discord_markdown(&caps[1]),
-                    replacement
-                )
-            })
-            .to_string()
-    }
-
+    /// Relays an IRC message through the webhook. If the server gave the message an ID, it's
+    /// remembered so Discord replies to it can be sent as IRC replies.
     pub(crate) async fn send_to_discord_webhook_relay(
         &self,
         nickname: &str,
         message: &str,
         avatar_url: Option<String>,
+        irc_message_id: Option<&str>,
     ) {
-        self.np_someone_talked.store(true, Ordering::Release);
-        self.send_to_discord_webhook(nickname, message, avatar_url)
+        self.mark_someone_talked();
+        let discord_message_id = self
+            .send_to_discord_webhook(nickname, message, avatar_url)
             .await;
+        if let (Some(discord_message_id), Some(irc_message_id)) =
+            (discord_message_id, irc_message_id)
+        {
+            const MAX_RELAYED_IDS: usize = 500;
+            let mut relayed_ids = self
+                .irc_message_ids
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if relayed_ids.len() >= MAX_RELAYED_IDS {
+                relayed_ids.pop_front();
+            }
+            relayed_ids.push_back((discord_message_id, irc_message_id.to_owned()));
+        }
+    }
+
+    /// The IRC message ID of a message that was relayed to Discord.
+    pub(crate) fn get_irc_message_id(&self, discord_message_id: MessageId) -> Option<String> {
+        self.irc_message_ids
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|(id, _)| *id == discord_message_id)
+            .map(|(_, irc_message_id)| irc_message_id.clone())
     }
 
     pub(crate) async fn send_to_discord_webhook(
@@ -122,36 +191,51 @@ impl Context {
         nickname: &str,
         message: &str,
         avatar_url: Option<String>,
-    ) {
+    ) -> Option<MessageId> {
+        // Translate IRC formatting to Discord formatting and strip colour coding
+        let message = match message
+            .strip_prefix("\x01ACTION ")
+            .and_then(|action| action.strip_suffix('\x01'))
+        {
+            Some(action) => {
+                let mut spans = formatting::parse_irc(action);
+                for span in &mut spans {
+                    span.style.italic = true;
+                }
+                formatting::render_markdown(&spans)
+            }
+            None => formatting::irc_to_discord(message),
+        };
+
+        self.execute_discord_webhook(nickname, message, avatar_url)
+            .await
+    }
+
+    /// Sends a message through the relay webhook as-is, without translating IRC formatting.
+    pub(crate) async fn execute_discord_webhook(
+        &self,
+        nickname: &str,
+        message: String,
+        avatar_url: Option<String>,
+    ) -> Option<MessageId> {
         let webhook = match Webhook::from_url(&self.discord_http, &self.discord_webhook_url).await {
             Ok(webhook) => webhook,
             Err(error) => {
                 error!("Failed to get webhook from URL: {:?}", error);
-                return;
+                return None;
             }
         };
 
-        // Translate IRC formatting to Discord formatting and strip colour coding
-        let action_regex = Regex::new(r"^\x01ACTION (.*)\x01$").unwrap();
-        let message = action_regex
-            .replace_all(message, |caps: &regex::Captures| {
-                format!("_{}_", Self::escape_discord_markdown(&caps[1]))
-            })
-            .to_string();
-        let message = self.translate_control_character(0x02, "**", &message);
-        let colour_regex = Regex::new(r"\x03(?:\d{1,2}(?:,\d{1,2})?)?").unwrap();
-        let message = colour_regex.replace_all(&message, "").to_string();
-        let message = self.translate_control_character(0x1D, "*", &message);
-        let message = self.translate_control_character(0x1E, "~~", &message);
-        let message = self.translate_control_character(0x1F, "__", &message);
-        let message = message.replace('|', "\\|");
-
         let mut builder = ExecuteWebhook::new().username(nickname).content(message);
         if let Some(avatar_url) = avatar_url {
             builder = builder.avatar_url(avatar_url);
         }
-        if let Err(error) = webhook.execute(&self.discord_http, false, builder).await {
-            error!("Failed to execute webhook: {:?}", error);
+        match webhook.execute(&self.discord_http, true, builder).await {
+            Ok(message) => message.map(|message| message.id),
+            Err(error) => {
+                error!("Failed to execute webhook: {:?}", error);
+                None
+            }
         }
     }
 
@@ -166,32 +250,157 @@ impl Context {
         channel: &str,
         nick: Option<&str>,
     ) {
-        let irc_sender = self.irc_sender.read().unwrap();
+        self.send_lines_to_irc(message, channel, nick, None, None)
+            .await;
+    }
+
+    /// Relays a Discord message to IRC, pointing to the original message if it had to be cut short.
+    /// Replies to relayed IRC messages are tagged as IRC replies where the server supports it.
+    pub(crate) async fn send_relay_to_irc(
+        &self,
+        message: &str,
+        nick: &str,
+        message_link: &str,
+        reply_to: Option<&str>,
+    ) {
+        self.send_lines_to_irc(
+            message,
+            &self.irc_channel,
+            Some(nick),
+            Some(message_link),
+            reply_to,
+        )
+        .await;
+    }
+
+    async fn send_lines_to_irc(
+        &self,
+        message: &str,
+        channel: &str,
+        nick: Option<&str>,
+        message_link: Option<&str>,
+        reply_to: Option<&str>,
+    ) {
+        const MAX_RELAY_LINES: usize = 5;
         let prefix = nick.map_or(String::new(), |n| format!("<{}> ", n));
-        let line_count = message.lines().count();
-        for (index, line) in message.lines().enumerate() {
-            let suffix = if nick.is_some() && index >= 4 && index < line_count - 1 {
-                "... (truncated)"
-            } else {
-                ""
+        let max_bytes = Self::irc_max_message_bytes(channel, &prefix);
+        let mut lines = irc_split::split_message(message, max_bytes);
+        if nick.is_some() && lines.len() > MAX_RELAY_LINES {
+            lines.truncate(MAX_RELAY_LINES - 1);
+            lines.push(match message_link {
+                Some(link) => format!("... (continued on Discord: {})", link),
+                None => "... (truncated)".to_owned(),
+            });
+        }
+
+        let reply_tags = reply_to
+            .filter(|_| {
+                self.irc_caps
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .contains("message-tags")
+            })
+            .map(|reply_to| vec![Tag("+draft/reply".to_owned(), Some(reply_to.to_owned()))]);
+        // Relayed Discord messages wait out IRC outages rather than getting lost.
+        let mut irc_link = self.irc_link.lock().unwrap_or_else(PoisonError::into_inner);
+        let buffer = nick.is_some() && !irc_link.is_connected();
+        let irc_sender = self
+            .irc_sender
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        for (index, line) in lines.into_iter().enumerate() {
+            let message = Message {
+                tags: if index == 0 { reply_tags.clone() } else { None },
+                prefix: None,
+                command: Command::PRIVMSG(channel.to_owned(), format!("{}{}", prefix, line)),
             };
-            if let Err(error) =
-                irc_sender.send_privmsg(channel, format!("{}{}{}", prefix, line, suffix))
-            {
+            if buffer {
+                irc_link.buffer(message);
+            } else if let Err(error) = irc_sender.send(message) {
                 error!("Error sending message to IRC: {:?}", error);
             }
-            if nick.is_some() && index >= 4 {
-                break;
-            }
         }
     }
 
+    /// The number of bytes left for the message text in a `PRIVMSG` to `channel`, given the 512
+    /// byte IRC line limit.
+    pub(crate) fn irc_max_message_bytes(channel: &str, prefix: &str) -> usize {
+        const IRC_MAX_LINE_BYTES: usize = 512;
+        // Room for the `:nick!user@host ` source the server prepends when relaying our message.
+        const IRC_SOURCE_BYTES: usize = 100;
+        let overhead = format!("PRIVMSG {} :", channel).len() + "\r\n".len() + prefix.len();
+        IRC_MAX_LINE_BYTES.saturating_sub(IRC_SOURCE_BYTES + overhead)
+    }
+
     pub(crate) async fn set_irc_topic(&self, topic: String) -> Result<()> {
-        let irc_sender = self.irc_sender.read().unwrap();
+        let irc_sender = self
+            .irc_sender
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
         irc_sender.send(Command::TOPIC(self.irc_channel.to_string(), Some(topic)))?;
         Ok(())
     }
 
+    /// Saves the rolling now playing message, so it carries on after a restart.
+    pub(crate) fn save_np_state(&self) {
+        // Saves from different threads would share the temporary files.
+        static SAVING: Mutex<()> = Mutex::new(());
+        let _saving = SAVING.lock().unwrap_or_else(PoisonError::into_inner);
+        storage::save(
+            NP_STATE_STORAGE_NAME,
+            &*self.np_state.lock().unwrap_or_else(PoisonError::into_inner),
+        );
+        storage::save(
+            NP_SOMEONE_TALKED_STORAGE_NAME,
+            &self.np_someone_talked.load(Ordering::Acquire),
+        );
+    }
+
+    /// Saves the now playing state on a blocking thread. This only happens when the message or
+    /// the flag changes, the latest lines are saved on shutdown.
+    fn save_np_state_in_background(&self) {
+        let context = self.clone();
+        tokio::task::spawn_blocking(move || context.save_np_state());
+    }
+
+    /// Notes that the now playing message is no longer the latest in the channel.
+    pub(crate) fn mark_someone_talked(&self) {
+        if !self.np_someone_talked.swap(true, Ordering::AcqRel) {
+            self.save_np_state_in_background();
+        }
+    }
+
+    /// Only resumes editing the saved now playing message if it's still the latest message in the
+    /// channel, as it would be if nobody talked while the bot was down.
+    pub(crate) async fn restore_np_state(&self) {
+        let Some(message_id) = self
+            .np_state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .message_id
+        else {
+            return;
+        };
+        let latest = self
+            .discord_channel
+            .messages(&self.discord_http, GetMessages::new().limit(1))
+            .await;
+        match latest {
+            Ok(messages)
+                if messages
+                    .first()
+                    .is_some_and(|latest| latest.id == message_id) =>
+            {
+                info!("Resuming now playing message {}", message_id);
+                return;
+            }
+            Ok(_) => info!("Now playing message is no longer the latest, starting a new one"),
+            Err(error) => warn!("Error checking now playing message: {:?}", error),
+        }
+        *self.np_state.lock().unwrap_or_else(PoisonError::into_inner) = NpState::default();
+        self.save_np_state_in_background();
+    }
+
     async fn send_np_to_discord(&self, message: &str, replace_last: bool) {
         const MAX_NP_LINES: usize = 5;
         let message = message.replace('|', "\\|");
@@ -205,7 +414,7 @@ impl Context {
         }
 
         let action = {
-            let mut state = self.np_state.lock().unwrap();
+            let mut state = self.np_state.lock().unwrap_or_else(PoisonError::into_inner);
             if someone_talked {
                 state.message_id = None;
                 state.lines.clear();
@@ -225,11 +434,17 @@ impl Context {
             }
         }; // MutexGuard dropped here
 
+        // Only a new message or a reset flag is worth saving right away.
+        let mut changed = someone_talked;
         match action {
             NpAction::SendNew(content) => {
                 match self.discord_channel.say(&self.discord_http, &content).await {
                     Ok(sent_msg) => {
-                        self.np_state.lock().unwrap().message_id = Some(sent_msg.id);
+                        self.np_state
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .message_id = Some(sent_msg.id);
+                        changed = true;
                     }
                     Err(e) => error!("Error sending NP message to Discord: {:?}", e),
                 }
@@ -245,6 +460,9 @@ impl Context {
                 }
             }
         }
+        if changed {
+            self.save_np_state_in_background();
+        }
     }
 
     pub(crate) async fn send_np_action(&self, action: &str, replace_last: bool) {
@@ -1,17 +1,136 @@
-use crate::{commands, context};
+use crate::accounts::ChatAccount;
+use crate::commands::Network;
+use crate::{commands, context, mentions, paste};
 use log::error;
 use serenity::async_trait;
-use serenity::model::channel::Message;
+use serenity::model::channel::{Attachment, Embed, Message, MessageReferenceKind, Poll};
+use serenity::model::event::MessageUpdateEvent;
+use serenity::model::id::MessageId;
+use serenity::model::sticker::StickerItem;
+use serenity::model::user::OnlineStatus;
 use serenity::prelude::*;
 use std::env;
 use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
 
 pub(crate) struct CommandContext;
 
+/// How many relayed messages are remembered for link previews that show up after them.
+const MAX_RELAYED_MESSAGES: usize = 100;
+
+/// A Discord message relayed to IRC. Discord usually adds link previews a moment after the message
+/// is sent, so they're relayed as a follow-up line.
+pub(crate) struct RelayedMessage {
+    id: MessageId,
+    nickname: String,
+    content: String,
+    link: String,
+    embed_count: usize,
+}
+
 impl TypeMapKey for CommandContext {
     type Value = context::Context;
 }
 
+/// Returns the display names of everyone online who can see the bridged channel, sorted by name.
+pub(crate) fn get_online_members(context: &context::Context) -> Vec<String> {
+    let Some(guild) = context
+        .discord_cache
+        .guilds()
+        .into_iter()
+        .filter_map(|guild_id| context.discord_cache.guild(guild_id))
+        .find(|guild| guild.channels.contains_key(&context.discord_channel))
+    else {
+        return Vec::new();
+    };
+    let Some(channel) = guild.channels.get(&context.discord_channel) else {
+        return Vec::new();
+    };
+    let mut names = guild
+        .presences
+        .values()
+        .filter(|presence| {
+            !matches!(
+                presence.status,
+                OnlineStatus::Offline | OnlineStatus::Invisible
+            )
+        })
+        .filter_map(|presence| guild.members.get(&presence.user.id))
+        .filter(|member| {
+            !member.user.bot && guild.user_permissions_in(channel, member).view_channel()
+        })
+        .map(|member| member.display_name().to_owned())
+        .collect::<Vec<_>>();
+    names.sort_by_key(|name| name.to_lowercase());
+    names
+}
+
+/// Describes what a message has besides its text: attachment URLs, stickers with their image URL,
+/// and a one-line summary of each embed. Embed text is escaped so it isn't read as markdown.
+fn describe_extras(
+    content: &str,
+    attachments: &[Attachment],
+    embeds: &[Embed],
+    stickers: &[StickerItem],
+) -> Vec<String> {
+    let mut parts = attachments
+        .iter()
+        .map(|attachment| attachment.proxy_url.clone())
+        .collect::<Vec<_>>();
+    for sticker in stickers {
+        let name = context::Context::escape_discord_markdown(&sticker.name);
+        parts.push(match sticker.image_url() {
+            Some(url) => format!("[sticker: {name}] {url}"),
+            None => format!("[sticker: {name}]"),
+        });
+    }
+    for embed in embeds {
+        let title = embed
+            .title
+            .clone()
+            .or_else(|| embed.author.as_ref().map(|author| author.name.clone()))
+            .map(|title| context::Context::escape_discord_markdown(&title));
+        // Image and video previews of a link in the message don't add anything.
+        let url = embed
+            .url
+            .clone()
+            .filter(|url| !content.contains(url.as_str()));
+        let summary = match (title, url) {
+            (Some(title), Some(url)) => format!("{title} ({url})"),
+            (Some(title), None) => title,
+            (None, Some(url)) => url,
+            (None, None) => continue,
+        };
+        match embed
+            .provider
+            .as_ref()
+            .and_then(|provider| provider.name.as_ref())
+        {
+            Some(provider) => parts.push(format!(
+                "[{}] {summary}",
+                context::Context::escape_discord_markdown(provider)
+            )),
+            None => parts.push(summary),
+        }
+    }
+    parts
+}
+
+fn describe_poll(poll: &Poll) -> String {
+    let answers = poll
+        .answers
+        .iter()
+        .filter_map(|answer| answer.poll_media.text.as_deref())
+        .map(context::Context::escape_discord_markdown)
+        .collect::<Vec<_>>();
+    let question = poll.question.text.as_deref().unwrap_or_default();
+    format!(
+        "[poll] {} ({})",
+        context::Context::escape_discord_markdown(question),
+        answers.join(" / ")
+    )
+}
+
 pub(crate) async fn get_serenity_client() -> Client {
     let token = env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN must be set");
     let intents = GatewayIntents::non_privileged()
@@ -40,35 +159,81 @@ impl EventHandler for Handler {
             .unwrap()
             .clone();
 
-        if msg.channel_id != context.discord_channel || msg.webhook_id.is_some() || msg.author.bot {
+        context.metrics.heartbeat("discord");
+        if msg.webhook_id.is_some() || msg.author.bot {
             return;
         }
-
-        context.np_someone_talked.store(true, Ordering::Release);
-
-        let mut message = msg.content.clone();
-
-        // Replace Discord user mentions (<@ID>) with display names
-        for user in &msg.mentions {
-            let display_name = user
-                .nick_in(&context.discord_http, msg.guild_id.unwrap_or_default())
-                .await
-                .unwrap_or_else(|| {
-                    user.global_name
-                        .clone()
-                        .unwrap_or_else(|| user.name.clone())
-                });
-            message = message.replace(&format!("<@{}>", user.id), &format!("@{display_name}"));
+        let private = msg.guild_id.is_none();
+        if commands::is_link_command(&context, &msg.content)
+            && (private || msg.channel_id == context.discord_channel)
+        {
+            // Link codes stay off the relay, and the reply only goes to the sender.
+            let nickname = msg.author.global_name.as_ref().unwrap_or(&msg.author.name);
+            let account = ChatAccount {
+                network: Network::Discord,
+                account: msg.author.id.to_string(),
+            };
+            let reply = commands::handle_link(
+                &context,
+                nickname,
+                Some(account),
+                &msg.content[1..],
+                private,
+            )
+            .await;
+            if let Err(error) = msg.channel_id.say(&context.discord_http, reply).await {
+                error!("Error replying to link command: {:?}", error);
+            }
+            return;
+        }
+        if msg.channel_id != context.discord_channel {
+            return;
         }
 
-        for attachment in &msg.attachments {
-            if !message.is_empty() {
-                message.push_str(&format!(" - {})", attachment.proxy_url));
-            } else {
-                message.push_str(&attachment.proxy_url.to_string());
-            }
+        context.mark_someone_talked();
+
+        let mut parts = vec![msg.content.clone()];
+        parts.extend(describe_extras(
+            &msg.content,
+            &msg.attachments,
+            &msg.embeds,
+            &msg.sticker_items,
+        ));
+        if let Some(poll) = &msg.poll {
+            parts.push(describe_poll(poll));
         }
+        for snapshot in &msg.message_snapshots {
+            let mut forwarded = vec![snapshot.content.clone()];
+            forwarded.extend(describe_extras(
+                &snapshot.content,
+                &snapshot.attachments,
+                &snapshot.embeds,
+                &snapshot.sticker_items,
+            ));
+            forwarded.retain(|part| !part.is_empty());
+            parts.push(format!("[forwarded] {}", forwarded.join(" - ")));
+        }
+        parts.retain(|part| !part.is_empty());
+        let mentions = msg
+            .mentions
+            .iter()
+            .chain(
+                msg.message_snapshots
+                    .iter()
+                    .flat_map(|snapshot| &snapshot.mentions),
+            )
+            .cloned()
+            .collect::<Vec<_>>();
+        let message =
+            mentions::resolve_mentions(&context, msg.guild_id, &parts.join(" - "), &mentions).await;
 
+        let message_link = msg.link();
+        let reply_to = msg
+            .message_reference
+            .as_ref()
+            .filter(|reference| reference.kind == MessageReferenceKind::Default)
+            .and_then(|reference| reference.message_id)
+            .and_then(|message_id| context.get_irc_message_id(message_id));
         let nickname = msg
             .author_nick(&context.discord_http)
             .await
@@ -81,16 +246,110 @@ impl EventHandler for Handler {
             .unwrap()
             .to_string();
 
-        let message = context.discord_markdown_to_irc(&message);
-        context.send_to_irc(&message, Some(&nickname)).await;
+        let irc_message = context.discord_markdown_to_irc(&message);
+        // Long messages and code blocks go to the paste backend instead of flooding IRC.
+        let message = paste::paste_long_message(&context, &message, &irc_message, &nickname)
+            .await
+            .unwrap_or(irc_message);
+        context
+            .send_relay_to_irc(&message, &nickname, &message_link, reply_to.as_deref())
+            .await;
+        context
+            .metrics
+            .relayed_discord_to_irc
+            .fetch_add(1, Ordering::Relaxed);
+        {
+            let mut relayed = context
+                .discord_relayed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if relayed.len() >= MAX_RELAYED_MESSAGES {
+                relayed.pop_front();
+            }
+            relayed.push_back(RelayedMessage {
+                id: msg.id,
+                nickname: nickname.clone(),
+                content: msg.content.clone(),
+                link: message_link.clone(),
+                embed_count: msg.embeds.len(),
+            });
+        }
 
         if msg.content.starts_with(&context.command_prefix) {
+            let is_admin = msg
+                .guild_id
+                .and_then(|guild_id| context.discord_cache.guild(guild_id))
+                .and_then(|guild| {
+                    let channel = guild.channels.get(&msg.channel_id)?;
+                    let member = guild.members.get(&msg.author.id)?;
+                    Some(guild.user_permissions_in(channel, member).manage_guild())
+                })
+                .unwrap_or(false);
             let command = &msg.content[1..];
-            if let Err(error) =
-                commands::handle_command(&context, &channel, &nickname, command, false).await
+            if let Err(error) = commands::handle_command(
+                &context,
+                Network::Discord,
+                &channel,
+                &nickname,
+                command,
+                is_admin,
+                Some(ChatAccount {
+                    network: Network::Discord,
+                    account: msg.author.id.to_string(),
+                }),
+            )
+            .await
             {
                 error!("Error handling command {}: {:?}", command, error);
             }
         }
     }
+
+    /// Relays link previews that Discord added to a message after it was relayed.
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        let context = ctx
+            .data
+            .read()
+            .await
+            .get::<CommandContext>()
+            .unwrap()
+            .clone();
+
+        let Some(embeds) = event.embeds else {
+            return;
+        };
+        let (nickname, content, link, relayed_embeds) = {
+            let mut relayed = context
+                .discord_relayed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let Some(message) = relayed.iter_mut().find(|message| message.id == event.id) else {
+                return;
+            };
+            if embeds.len() <= message.embed_count {
+                return;
+            }
+            let relayed_embeds = std::mem::replace(&mut message.embed_count, embeds.len());
+            (
+                message.nickname.clone(),
+                message.content.clone(),
+                message.link.clone(),
+                relayed_embeds,
+            )
+        };
+        let parts = describe_extras(&content, &[], &embeds[relayed_embeds..], &[]);
+        if parts.is_empty() {
+            return;
+        }
+        let message = context.discord_markdown_to_irc(&format!("↳ {}", parts.join(" - ")));
+        context
+            .send_relay_to_irc(&message, &nickname, &link, None)
+            .await;
+    }
 }
@@ -0,0 +1,268 @@
+use crate::api::ScheduleResponse;
+use crate::context::Context;
+use crate::{scheduler, storage};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serenity::all::{
+    CreateScheduledEvent, EditScheduledEvent, GuildId, ScheduledEventId, ScheduledEventStatus,
+    ScheduledEventType,
+};
+use std::collections::{HashMap, HashSet};
+
+pub(crate) const STORAGE_NAME: &str = "scheduled_events";
+
+/// Discord limits event names to 100 and descriptions to 1000 characters.
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 1000;
+
+/// A Discord scheduled event created for an AzuraCast schedule entry.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SyncedEvent {
+    pub(crate) event_id: ScheduledEventId,
+    pub(crate) start_timestamp: u64,
+    pub(crate) end_timestamp: u64,
+    pub(crate) title: String,
+    pub(crate) active: bool,
+}
+
+/// The scheduled events we manage, keyed by their `ScheduleResponse.id`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SyncedEvents {
+    events: HashMap<u64, SyncedEvent>,
+    /// Shows that already ended, by schedule ID and start time. AzuraCast keeps listing a show
+    /// until its scheduled end, so these stop it from getting a new event.
+    completed: HashSet<(u64, u64)>,
+}
+
+async fn get_guild_id(context: &Context) -> Result<GuildId> {
+    context
+        .discord_channel
+        .to_channel(&context.discord_http)
+        .await?
+        .guild()
+        .map(|channel| channel.guild_id)
+        .ok_or_else(|| anyhow!("Discord channel is not in a guild"))
+}
+
+fn to_datetime(timestamp: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp as i64, 0).unwrap_or_default()
+}
+
+fn truncate(text: &str, max_length: usize) -> String {
+    text.chars().take(max_length).collect()
+}
+
+/// Creates, updates and removes Discord scheduled events so they mirror the AzuraCast schedule.
+pub(crate) async fn sync(context: &Context, entries: &[ScheduleResponse]) -> Result<()> {
+    let Some(synced_events) = &context.scheduled_events else {
+        return Ok(());
+    };
+    let guild_id = get_guild_id(context).await?;
+    let now = Utc::now();
+
+    let mut synced_events = synced_events.lock().await;
+    let SyncedEvents { events, completed } = &mut *synced_events;
+    completed.retain(|(id, start_timestamp)| {
+        entries
+            .iter()
+            .any(|entry| entry.id == *id && entry.start_timestamp == *start_timestamp)
+    });
+
+    // Recurring shows share an ID, so only the next occurrence of each gets an event.
+    let mut upcoming: HashMap<u64, &ScheduleResponse> = HashMap::new();
+    for entry in entries {
+        if completed.contains(&(entry.id, entry.start_timestamp)) {
+            continue;
+        }
+        upcoming
+            .entry(entry.id)
+            .and_modify(|existing| {
+                if entry.start_timestamp < existing.start_timestamp {
+                    *existing = entry;
+                }
+            })
+            .or_insert(entry);
+    }
+
+    // Shows that dropped off the schedule have either ended or were cancelled.
+    let stale_ids = events
+        .keys()
+        .filter(|id| !upcoming.contains_key(id))
+        .copied()
+        .collect::<Vec<_>>();
+    for id in stale_ids {
+        let event = events.remove(&id).unwrap();
+        let result = if event.active {
+            guild_id
+                .edit_scheduled_event(
+                    &context.discord_http,
+                    event.event_id,
+                    EditScheduledEvent::new().status(ScheduledEventStatus::Completed),
+                )
+                .await
+                .map(|_| ())
+        } else {
+            guild_id
+                .delete_scheduled_event(&context.discord_http, event.event_id)
+                .await
+        };
+        if let Err(error) = result {
+            warn!(
+                "Could not remove scheduled event {}: {:?}",
+                event.event_id, error
+            );
+        }
+    }
+
+    for (id, entry) in upcoming {
+        let title = truncate(&entry.title, MAX_NAME_LENGTH);
+        match events.get_mut(&id) {
+            Some(event) if event.active => {}
+            Some(event)
+                if event.start_timestamp == entry.start_timestamp
+                    && event.end_timestamp == entry.end_timestamp
+                    && event.title == title => {}
+            Some(event) => {
+                let mut builder = EditScheduledEvent::new()
+                    .name(&title)
+                    .end_time(to_datetime(entry.end_timestamp));
+                // Discord refuses to move an event's start time into the past.
+                if to_datetime(entry.start_timestamp) > now {
+                    builder = builder.start_time(to_datetime(entry.start_timestamp));
+                }
+                match guild_id
+                    .edit_scheduled_event(&context.discord_http, event.event_id, builder)
+                    .await
+                {
+                    Ok(_) => {
+                        event.start_timestamp = entry.start_timestamp;
+                        event.end_timestamp = entry.end_timestamp;
+                        event.title = title;
+                    }
+                    Err(error) => error!("Could not update scheduled event: {:?}", error),
+                }
+            }
+            None => {
+                if to_datetime(entry.end_timestamp) <= now {
+                    continue;
+                }
+                let start =
+                    to_datetime(entry.start_timestamp).max(now + chrono::Duration::minutes(1));
+                let mut builder =
+                    CreateScheduledEvent::new(ScheduledEventType::External, &title, start)
+                        .end_time(to_datetime(entry.end_timestamp))
+                        .location(&context.stream_url);
+                if !entry.description.is_empty() {
+                    builder =
+                        builder.description(truncate(&entry.description, MAX_DESCRIPTION_LENGTH));
+                }
+                match guild_id
+                    .create_scheduled_event(&context.discord_http, builder)
+                    .await
+                {
+                    Ok(event) => {
+                        events.insert(
+                            id,
+                            SyncedEvent {
+                                event_id: event.id,
+                                start_timestamp: entry.start_timestamp,
+                                end_timestamp: entry.end_timestamp,
+                                title,
+                                active: false,
+                            },
+                        );
+                    }
+                    Err(error) => error!("Could not create scheduled event: {:?}", error),
+                }
+            }
+        }
+    }
+
+    storage::save(STORAGE_NAME, &*synced_events);
+    Ok(())
+}
+
+/// Starts the scheduled event for the show that is on right now, if there is one, counting DJs who
+/// start early within [`scheduler::EARLY_START_SECONDS`].
+pub(crate) async fn mark_active(context: &Context) {
+    let Some(synced_events) = &context.scheduled_events else {
+        return;
+    };
+    let guild_id = match get_guild_id(context).await {
+        Ok(guild_id) => guild_id,
+        Err(error) => {
+            error!("Could not get guild for scheduled events: {:?}", error);
+            return;
+        }
+    };
+    let now = Utc::now().timestamp() as u64;
+    let early_start = scheduler::EARLY_START_SECONDS as u64;
+    let mut synced_events = synced_events.lock().await;
+    // A show that's on already wins over one that starts soon.
+    let Some(event) = synced_events
+        .events
+        .values_mut()
+        .filter(|event| {
+            !event.active && event.start_timestamp <= now + early_start && now < event.end_timestamp
+        })
+        .min_by_key(|event| {
+            (
+                event.start_timestamp > now,
+                event.start_timestamp.abs_diff(now),
+            )
+        })
+    else {
+        return;
+    };
+    match guild_id
+        .edit_scheduled_event(
+            &context.discord_http,
+            event.event_id,
+            EditScheduledEvent::new().status(ScheduledEventStatus::Active),
+        )
+        .await
+    {
+        Ok(_) => event.active = true,
+        Err(error) => error!("Could not start scheduled event: {:?}", error),
+    }
+    storage::save(STORAGE_NAME, &*synced_events);
+}
+
+/// Completes all active scheduled events once the live show ends, remembering them so the next
+/// sync doesn't create them again.
+pub(crate) async fn mark_completed(context: &Context) {
+    let Some(synced_events) = &context.scheduled_events else {
+        return;
+    };
+    let guild_id = match get_guild_id(context).await {
+        Ok(guild_id) => guild_id,
+        Err(error) => {
+            error!("Could not get guild for scheduled events: {:?}", error);
+            return;
+        }
+    };
+    let mut synced_events = synced_events.lock().await;
+    let SyncedEvents { events, completed } = &mut *synced_events;
+    let active_ids = events
+        .iter()
+        .filter(|(_, event)| event.active)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in active_ids {
+        let event = events.remove(&id).unwrap();
+        completed.insert((id, event.start_timestamp));
+        if let Err(error) = guild_id
+            .edit_scheduled_event(
+                &context.discord_http,
+                event.event_id,
+                EditScheduledEvent::new().status(ScheduledEventStatus::Completed),
+            )
+            .await
+        {
+            error!("Could not complete scheduled event: {:?}", error);
+        }
+    }
+    storage::save(STORAGE_NAME, &*synced_events);
+}
@@ -0,0 +1,93 @@
+use super::{split_lines, Span, Style};
+
+const ESCAPE: &str = "\x1b[";
+
+/// The closest of Discord's eight ANSI foreground colours to each of the 16 mIRC colours.
+const FOREGROUND: [u8; 16] = [
+    37, // white
+    30, // black
+    34, // blue
+    32, // green
+    31, // red
+    31, // brown
+    35, // purple
+    33, // orange
+    33, // yellow
+    32, // light green
+    36, // cyan
+    36, // light cyan
+    34, // light blue
+    35, // pink
+    30, // grey
+    37, // light grey
+];
+
+/// The closest of Discord's eight ANSI background colours to each of the 16 mIRC colours.
+const BACKGROUND: [u8; 16] = [
+    47, // white
+    40, // black
+    40, // blue
+    43, // green
+    41, // red
+    41, // brown
+    45, // purple
+    41, // orange
+    47, // yellow
+    43, // light green
+    43, // cyan
+    46, // light cyan
+    45, // light blue
+    45, // pink
+    42, // grey
+    46, // light grey
+];
+
+/// The SGR sequence that switches to a style from a clean state. Formatting Discord doesn't
+/// support in ANSI blocks, and the extended mIRC colours, are dropped.
+fn sgr(style: &Style) -> String {
+    let (foreground, background) = if style.reverse {
+        (style.background.or(Some(1)), style.foreground.or(Some(0)))
+    } else {
+        (style.foreground, style.background)
+    };
+    let mut codes = vec![0];
+    if style.bold {
+        codes.push(1);
+    }
+    if style.underline {
+        codes.push(4);
+    }
+    if let Some(colour) = foreground.and_then(|c| FOREGROUND.get(c as usize)) {
+        codes.push(*colour);
+    }
+    if let Some(colour) = background.and_then(|c| BACKGROUND.get(c as usize)) {
+        codes.push(*colour);
+    }
+    let codes = codes.iter().map(u8::to_string).collect::<Vec<_>>();
+    format!("{ESCAPE}{}m", codes.join(";"))
+}
+
+/// Renders spans as text with ANSI escape codes, for use in a Discord ```ansi code block.
+pub(crate) fn render_ansi(spans: &[Span]) -> String {
+    let mut lines = Vec::new();
+    for line in split_lines(spans) {
+        let mut output = String::new();
+        let reset = sgr(&Style::default());
+        let mut active = reset.clone();
+        for span in line {
+            // Styles can differ only in ways ANSI can't show, so compare the codes themselves.
+            let codes = sgr(&span.style);
+            if codes != active {
+                output.push_str(&codes);
+                active = codes;
+            }
+            // Nothing inside the block can end it early.
+            output.push_str(&span.text.replace("```", "`\u{200b}``"));
+        }
+        if active != reset {
+            output.push_str(&reset);
+        }
+        lines.push(output);
+    }
+    lines.join("\n")
+}
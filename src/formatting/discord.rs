@@ -0,0 +1,467 @@
+use super::{push_span, Span, Style};
+use crate::context::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    Bold,
+    Underline,
+    Italic,
+    Strikethrough,
+}
+
+impl Marker {
+    /// Markers in the order they're opened when rendering, outermost first.
+    const ALL: [Marker; 4] = [
+        Marker::Bold,
+        Marker::Underline,
+        Marker::Italic,
+        Marker::Strikethrough,
+    ];
+
+    fn markdown(&self) -> &'static str {
+        match self {
+            Marker::Bold => "**",
+            Marker::Underline => "__",
+            Marker::Italic => "*",
+            Marker::Strikethrough => "~~",
+        }
+    }
+
+    fn is_set(&self, style: &Style) -> bool {
+        match self {
+            Marker::Bold => style.bold,
+            Marker::Underline => style.underline,
+            Marker::Italic => style.italic,
+            Marker::Strikethrough => style.strikethrough,
+        }
+    }
+}
+
+/// A run of `*`, `_` or `~` that may open and/or close formatting.
+#[derive(Debug)]
+struct Delimiter {
+    character: char,
+    /// The number of characters in the run that haven't been matched yet.
+    length: usize,
+    can_open: bool,
+    can_close: bool,
+    opens: Vec<Marker>,
+    closes: Vec<Marker>,
+}
+
+#[derive(Debug)]
+enum Token {
+    Text(String),
+    Code(String),
+    Delimiter(Delimiter),
+}
+
+fn starts_with_at(chars: &[char], index: usize, pattern: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    chars.get(index..index + pattern.len()) == Some(&pattern[..])
+}
+
+fn run_length(chars: &[char], index: usize) -> usize {
+    chars[index..]
+        .iter()
+        .take_while(|c| **c == chars[index])
+        .count()
+}
+
+fn is_url_start(chars: &[char], index: usize) -> bool {
+    starts_with_at(chars, index, "https://") || starts_with_at(chars, index, "http://")
+}
+
+/// Finds the start of the first URL in `text` that isn't part of a longer word.
+fn find_url(text: &str) -> Option<usize> {
+    text.match_indices("http")
+        .map(|(index, _)| index)
+        .find(|index| {
+            let rest = &text[*index..];
+            (rest.starts_with("https://") || rest.starts_with("http://"))
+                && !text[..*index]
+                    .chars()
+                    .next_back()
+                    .is_some_and(char::is_alphanumeric)
+        })
+}
+
+/// Finds the next run of exactly `length` backticks at or after `index`.
+fn find_backticks(chars: &[char], mut index: usize, length: usize) -> Option<usize> {
+    while index < chars.len() {
+        if chars[index] == '`' {
+            let run = run_length(chars, index);
+            if run == length {
+                return Some(index);
+            }
+            index += run;
+        } else {
+            index += 1;
+        }
+    }
+    None
+}
+
+/// Parses a custom emoji (`<:name:id>` or `<a:name:id>`) at `index`, returning its name and
+/// length.
+fn parse_custom_emoji(chars: &[char], index: usize) -> Option<(String, usize)> {
+    let mut position = index + 1;
+    if chars.get(position) == Some(&'a') {
+        position += 1;
+    }
+    if chars.get(position) != Some(&':') {
+        return None;
+    }
+    position += 1;
+    let name_start = position;
+    while chars
+        .get(position)
+        .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+    {
+        position += 1;
+    }
+    if position == name_start || chars.get(position) != Some(&':') {
+        return None;
+    }
+    let name = chars[name_start..position].iter().collect();
+    position += 1;
+    let id_start = position;
+    while chars.get(position).is_some_and(char::is_ascii_digit) {
+        position += 1;
+    }
+    if position == id_start || chars.get(position) != Some(&'>') {
+        return None;
+    }
+    Some((name, position + 1 - index))
+}
+
+/// Strips the newlines around a code block and its language tag, if there is one.
+fn code_block_content(content: &str) -> String {
+    let content = match content.split_once('\n') {
+        Some((language, rest))
+            if !language.is_empty() && !language.contains(char::is_whitespace) =>
+        {
+            rest
+        }
+        _ => content,
+    };
+    content.trim_matches('\n').to_owned()
+}
+
+fn tokenize(message: &str) -> Vec<Token> {
+    let chars = message.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut index = 0;
+
+    macro_rules! push_token {
+        ($token:expr) => {{
+            if !text.is_empty() {
+                tokens.push(Token::Text(std::mem::take(&mut text)));
+            }
+            tokens.push($token);
+        }};
+    }
+
+    while index < chars.len() {
+        let character = chars[index];
+        match character {
+            '\\' if chars.get(index + 1).is_some_and(char::is_ascii_punctuation) => {
+                text.push(chars[index + 1]);
+                index += 2;
+            }
+            '`' => {
+                let run = run_length(&chars, index);
+                let end = if run >= 3 {
+                    find_backticks(&chars[index + 3..], 0, 3).map(|end| (3, index + 3 + end))
+                } else {
+                    find_backticks(&chars, index + run, run).map(|end| (run, end))
+                };
+                match end {
+                    Some((length, end)) => {
+                        let content = chars[index + length..end].iter().collect::<String>();
+                        let content = if length >= 3 {
+                            code_block_content(&content)
+                        } else if content.len() >= 2
+                            && content.starts_with(' ')
+                            && content.ends_with(' ')
+                            && !content.trim().is_empty()
+                        {
+                            // Like CommonMark, code that's only spaces is kept as it is.
+                            content[1..content.len() - 1].to_owned()
+                        } else {
+                            content
+                        };
+                        push_token!(Token::Code(content));
+                        index = end + length;
+                    }
+                    None => {
+                        text.extend(&chars[index..index + run]);
+                        index += run;
+                    }
+                }
+            }
+            '<' if is_url_start(&chars, index + 1) => {
+                // Links in angle brackets have their embed suppressed.
+                match chars[index..]
+                    .iter()
+                    .position(|c| *c == '>' || c.is_whitespace())
+                {
+                    Some(end) if chars[index + end] == '>' => {
+                        text.extend(&chars[index + 1..index + end]);
+                        index += end + 1;
+                    }
+                    _ => {
+                        text.push(character);
+                        index += 1;
+                    }
+                }
+            }
+            '<' => match parse_custom_emoji(&chars, index) {
+                Some((name, length)) => {
+                    text.push_str(&format!(":{name}:"));
+                    index += length;
+                }
+                None => {
+                    text.push(character);
+                    index += 1;
+                }
+            },
+            'h' if (index == 0 || !chars[index - 1].is_alphanumeric())
+                && is_url_start(&chars, index) =>
+            {
+                // URLs are left alone, so underscores and asterisks in them survive.
+                let length = chars[index..]
+                    .iter()
+                    .take_while(|c| !c.is_whitespace())
+                    .count();
+                text.extend(&chars[index..index + length]);
+                index += length;
+            }
+            '*' | '_' | '~' => {
+                let run = run_length(&chars, index);
+                let previous = index.checked_sub(1).map(|i| chars[i]);
+                let next = chars.get(index + run).copied();
+                let left_flanking = next.is_some_and(|c| !c.is_whitespace());
+                let right_flanking = previous.is_some_and(|c| !c.is_whitespace());
+                let (can_open, can_close) = match character {
+                    // Underscores inside words (snake_case) don't count.
+                    '_' => (
+                        left_flanking && !previous.is_some_and(char::is_alphanumeric),
+                        right_flanking && !next.is_some_and(char::is_alphanumeric),
+                    ),
+                    '~' if run < 2 => (false, false),
+                    _ => (left_flanking, right_flanking),
+                };
+                if can_open || can_close {
+                    push_token!(Token::Delimiter(Delimiter {
+                        character,
+                        length: run,
+                        can_open,
+                        can_close,
+                        opens: Vec::new(),
+                        closes: Vec::new(),
+                    }));
+                } else {
+                    text.extend(&chars[index..index + run]);
+                }
+                index += run;
+            }
+            _ => {
+                text.push(character);
+                index += 1;
+            }
+        }
+    }
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+fn delimiter(tokens: &mut [Token], index: usize) -> &mut Delimiter {
+    match &mut tokens[index] {
+        Token::Delimiter(delimiter) => delimiter,
+        _ => unreachable!("token {index} is not a delimiter"),
+    }
+}
+
+/// Pairs up opening and closing delimiters, innermost first. Delimiters that don't pair up, or
+/// that overlap with another pair, are left as literal text.
+fn match_delimiters(tokens: &mut [Token]) {
+    let mut openers: Vec<usize> = Vec::new();
+    for index in 0..tokens.len() {
+        let Token::Delimiter(closer) = &tokens[index] else {
+            continue;
+        };
+        let (character, can_open, can_close) =
+            (closer.character, closer.can_open, closer.can_close);
+        if can_close {
+            while delimiter(tokens, index).length > 0 {
+                let Some(position) = openers
+                    .iter()
+                    .rposition(|opener| delimiter(tokens, *opener).character == character)
+                else {
+                    break;
+                };
+                let opener = openers[position];
+                let opener_length = delimiter(tokens, opener).length;
+                let closer_length = delimiter(tokens, index).length;
+                let double = opener_length >= 2 && closer_length >= 2;
+                let (used, marker) = match (character, double) {
+                    ('*', true) => (2, Marker::Bold),
+                    ('*', false) => (1, Marker::Italic),
+                    ('_', true) => (2, Marker::Underline),
+                    ('_', false) => (1, Marker::Italic),
+                    ('~', true) => (2, Marker::Strikethrough),
+                    _ => break,
+                };
+                let opener_delimiter = delimiter(tokens, opener);
+                opener_delimiter.length -= used;
+                opener_delimiter.opens.push(marker);
+                let opener_exhausted = opener_delimiter.length == 0;
+                let closer_delimiter = delimiter(tokens, index);
+                closer_delimiter.length -= used;
+                closer_delimiter.closes.push(marker);
+
+                // Openers between the pair can no longer be closed.
+                openers.truncate(position + 1);
+                if opener_exhausted {
+                    openers.pop();
+                }
+            }
+        }
+        if can_open && delimiter(tokens, index).length > 0 {
+            openers.push(index);
+        }
+    }
+}
+
+pub(crate) fn parse_markdown(message: &str) -> Vec<Span> {
+    let mut tokens = tokenize(message);
+    match_delimiters(&mut tokens);
+
+    let mut spans = Vec::new();
+    let mut counts = [0usize; 4];
+    let style = |counts: &[usize; 4]| Style {
+        bold: counts[Marker::Bold as usize] > 0,
+        underline: counts[Marker::Underline as usize] > 0,
+        italic: counts[Marker::Italic as usize] > 0,
+        strikethrough: counts[Marker::Strikethrough as usize] > 0,
+        ..Style::default()
+    };
+    for token in tokens {
+        match token {
+            Token::Text(text) => push_span(&mut spans, &text, style(&counts)),
+            Token::Code(code) => push_span(
+                &mut spans,
+                &code,
+                Style {
+                    monospace: true,
+                    ..style(&counts)
+                },
+            ),
+            Token::Delimiter(delimiter) => {
+                for marker in &delimiter.closes {
+                    counts[*marker as usize] -= 1;
+                }
+                let literal = delimiter.character.to_string().repeat(delimiter.length);
+                push_span(&mut spans, &literal, style(&counts));
+                for marker in &delimiter.opens {
+                    counts[*marker as usize] += 1;
+                }
+            }
+        }
+    }
+    spans
+}
+
+/// Escapes markdown in plain text. URLs are left alone, as escaping them would break the link.
+fn escape_text(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = find_url(rest) {
+        let end = rest[start..]
+            .find(char::is_whitespace)
+            .map_or(rest.len(), |length| start + length);
+        output.push_str(&Context::escape_discord_markdown(&rest[..start]));
+        output.push_str(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    output.push_str(&Context::escape_discord_markdown(rest));
+    output
+}
+
+fn render_code(code: &str) -> String {
+    if code.contains('`') {
+        format!("`` {code} ``")
+    } else {
+        format!("`{code}`")
+    }
+}
+
+/// Moves whitespace at the edges of styled spans outside of the formatting, as Discord doesn't
+/// format text like `** bold **`.
+fn move_whitespace_out(spans: &[Span]) -> Vec<Span> {
+    let mut result = Vec::new();
+    for (index, span) in spans.iter().enumerate() {
+        let previous = index
+            .checked_sub(1)
+            .map_or(Style::default(), |i| spans[i].style);
+        let next = spans.get(index + 1).map_or(Style::default(), |s| s.style);
+        let trimmed_start = span.text.trim_start();
+        let core = trimmed_start.trim_end();
+        let leading = &span.text[..span.text.len() - trimmed_start.len()];
+        let trailing = &trimmed_start[core.len()..];
+        if core.is_empty() {
+            push_span(
+                &mut result,
+                &span.text,
+                span.style.intersect(&previous).intersect(&next),
+            );
+            continue;
+        }
+        push_span(&mut result, leading, span.style.intersect(&previous));
+        push_span(&mut result, core, span.style);
+        push_span(&mut result, trailing, span.style.intersect(&next));
+    }
+    result
+}
+
+pub(crate) fn render_markdown(spans: &[Span]) -> String {
+    let mut output = String::new();
+    let mut open: Vec<Marker> = Vec::new();
+    for span in move_whitespace_out(spans) {
+        // Close everything from the first marker that no longer applies, then reopen what's left.
+        if let Some(position) = open.iter().position(|marker| !marker.is_set(&span.style)) {
+            for marker in open.drain(position..).rev() {
+                output.push_str(marker.markdown());
+            }
+        }
+        let opening = Marker::ALL
+            .into_iter()
+            .filter(|marker| marker.is_set(&span.style) && !open.contains(marker))
+            .collect::<Vec<_>>();
+        // Formatting that was just closed and reopened needs the whitespace between the two.
+        let text = if opening.is_empty() {
+            span.text.as_str()
+        } else {
+            let trimmed = span.text.trim_start();
+            output.push_str(&span.text[..span.text.len() - trimmed.len()]);
+            trimmed
+        };
+        for marker in opening {
+            output.push_str(marker.markdown());
+            open.push(marker);
+        }
+        if span.style.monospace {
+            output.push_str(&render_code(text));
+        } else {
+            output.push_str(&escape_text(text));
+        }
+    }
+    for marker in open.iter().rev() {
+        output.push_str(marker.markdown());
+    }
+    output
+}
@@ -0,0 +1,175 @@
+use super::{push_span, split_lines, Span, Style};
+
+const BOLD: char = '\x02';
+const COLOUR: char = '\x03';
+const HEX_COLOUR: char = '\x04';
+const RESET: char = '\x0F';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1D';
+const STRIKETHROUGH: char = '\x1E';
+const UNDERLINE: char = '\x1F';
+const CTCP: char = '\x01';
+
+/// Reads up to `max_length` characters matching `predicate` starting at `index`.
+fn take_while(
+    chars: &[char],
+    index: usize,
+    max_length: usize,
+    predicate: fn(&char) -> bool,
+) -> &[char] {
+    let length = chars[index.min(chars.len())..]
+        .iter()
+        .take(max_length)
+        .take_while(|c| predicate(c))
+        .count();
+    &chars[index..index + length]
+}
+
+fn parse_number(digits: &[char]) -> u8 {
+    digits.iter().collect::<String>().parse().unwrap_or(0)
+}
+
+pub(crate) fn parse_irc(text: &str) -> Vec<Span> {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut current = String::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let character = chars[index];
+        index += 1;
+        if !matches!(
+            character,
+            BOLD | COLOUR
+                | HEX_COLOUR
+                | RESET
+                | MONOSPACE
+                | REVERSE
+                | ITALIC
+                | STRIKETHROUGH
+                | UNDERLINE
+                | CTCP
+        ) {
+            current.push(character);
+            continue;
+        }
+
+        push_span(&mut spans, &current, style);
+        current.clear();
+        match character {
+            BOLD => style.bold = !style.bold,
+            ITALIC => style.italic = !style.italic,
+            UNDERLINE => style.underline = !style.underline,
+            STRIKETHROUGH => style.strikethrough = !style.strikethrough,
+            MONOSPACE => style.monospace = !style.monospace,
+            REVERSE => style.reverse = !style.reverse,
+            RESET => style = Style::default(),
+            COLOUR => {
+                let foreground = take_while(&chars, index, 2, char::is_ascii_digit);
+                if foreground.is_empty() {
+                    style.foreground = None;
+                    style.background = None;
+                    continue;
+                }
+                index += foreground.len();
+                style.foreground = Some(parse_number(foreground));
+                if chars.get(index) == Some(&',') {
+                    let background = take_while(&chars, index + 1, 2, char::is_ascii_digit);
+                    if !background.is_empty() {
+                        index += 1 + background.len();
+                        style.background = Some(parse_number(background));
+                    }
+                }
+            }
+            HEX_COLOUR => {
+                // Hex colours can't be represented, so they're skipped.
+                let foreground = take_while(&chars, index, 6, char::is_ascii_hexdigit);
+                index += foreground.len();
+                if !foreground.is_empty() && chars.get(index) == Some(&',') {
+                    let background = take_while(&chars, index + 1, 6, char::is_ascii_hexdigit);
+                    if !background.is_empty() {
+                        index += 1 + background.len();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    push_span(&mut spans, &current, style);
+    spans
+}
+
+/// Appends the codes needed to go from one style to another. `next_text` is the text that follows,
+/// so colour codes can't accidentally swallow digits from it.
+fn push_transition(output: &mut String, from: &Style, to: &Style, next_text: &str) {
+    let mut from = *from;
+    let colour_changed = from.foreground != to.foreground || from.background != to.background;
+    let starts_with_digit = next_text.starts_with(|c: char| c.is_ascii_digit() || c == ',');
+    if colour_changed && to.foreground.is_none() && starts_with_digit {
+        // A bare colour code would eat the digits, so reset everything and start over.
+        output.push(RESET);
+        from = Style::default();
+    }
+
+    let toggles = [
+        (from.bold, to.bold, BOLD),
+        (from.italic, to.italic, ITALIC),
+        (from.underline, to.underline, UNDERLINE),
+        (from.strikethrough, to.strikethrough, STRIKETHROUGH),
+        (from.monospace, to.monospace, MONOSPACE),
+        (from.reverse, to.reverse, REVERSE),
+    ];
+    // Close formatting in the reverse order it's opened in, so the codes nest.
+    for (enabled_from, enabled_to, code) in toggles.iter().rev() {
+        if *enabled_from && !enabled_to {
+            output.push(*code);
+        }
+    }
+    for (enabled_from, enabled_to, code) in toggles {
+        if !enabled_from && enabled_to {
+            output.push(code);
+        }
+    }
+
+    if from.foreground == to.foreground && from.background == to.background {
+        return;
+    }
+    match to.foreground {
+        None => output.push(COLOUR),
+        Some(foreground) => {
+            // A background can only be removed by clearing the colour entirely.
+            if from.background.is_some() && to.background.is_none() {
+                output.push(COLOUR);
+            }
+            output.push_str(&format!("{COLOUR}{foreground:02}"));
+            if let Some(background) = to.background {
+                output.push_str(&format!(",{background:02}"));
+            } else if next_text.starts_with(',') {
+                // Keep a following comma from being read as a background colour.
+                output.push_str(&format!("{BOLD}{BOLD}"));
+            }
+        }
+    }
+}
+
+fn render_line(spans: &[Span]) -> String {
+    let mut output = String::new();
+    let mut active = Style::default();
+    for span in spans {
+        push_transition(&mut output, &active, &span.style, &span.text);
+        output.push_str(&span.text);
+        active = span.style;
+    }
+    push_transition(&mut output, &active, &Style::default(), "");
+    output
+}
+
+pub(crate) fn render_irc(spans: &[Span]) -> String {
+    split_lines(spans)
+        .iter()
+        .map(|line| render_line(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
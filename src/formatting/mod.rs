@@ -0,0 +1,97 @@
+//! Translation between Discord markdown and IRC formatting codes. Both sides are parsed into a
+//! list of [`Span`]s of text with a [`Style`], which is then rendered in the other format.
+
+mod ansi;
+mod discord;
+mod irc;
+#[cfg(test)]
+mod tests;
+
+pub(crate) use discord::{parse_markdown, render_markdown};
+pub(crate) use irc::{parse_irc, render_irc};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Style {
+    pub(crate) bold: bool,
+    pub(crate) italic: bool,
+    pub(crate) underline: bool,
+    pub(crate) strikethrough: bool,
+    pub(crate) monospace: bool,
+    pub(crate) reverse: bool,
+    pub(crate) foreground: Option<u8>,
+    pub(crate) background: Option<u8>,
+}
+
+impl Style {
+    /// The formatting two styles have in common.
+    pub(crate) fn intersect(&self, other: &Style) -> Style {
+        Style {
+            bold: self.bold && other.bold,
+            italic: self.italic && other.italic,
+            underline: self.underline && other.underline,
+            strikethrough: self.strikethrough && other.strikethrough,
+            monospace: self.monospace && other.monospace,
+            reverse: self.reverse && other.reverse,
+            foreground: self
+                .foreground
+                .filter(|_| self.foreground == other.foreground),
+            background: self
+                .background
+                .filter(|_| self.background == other.background),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Span {
+    pub(crate) text: String,
+    pub(crate) style: Style,
+}
+
+/// Appends text to a span list, merging it into the last span if the style is the same.
+pub(crate) fn push_span(spans: &mut Vec<Span>, text: &str, style: Style) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.style == style => last.text.push_str(text),
+        _ => spans.push(Span {
+            text: text.to_owned(),
+            style,
+        }),
+    }
+}
+
+/// Splits spans into lines at newlines, as IRC formatting doesn't carry over to the next line.
+pub(crate) fn split_lines(spans: &[Span]) -> Vec<Vec<Span>> {
+    let mut lines = vec![Vec::new()];
+    for span in spans {
+        for (index, text) in span.text.split('\n').enumerate() {
+            if index > 0 {
+                lines.push(Vec::new());
+            }
+            push_span(lines.last_mut().unwrap(), text, span.style);
+        }
+    }
+    lines
+}
+
+pub(crate) fn discord_to_irc(message: &str) -> String {
+    render_irc(&parse_markdown(message))
+}
+
+pub(crate) fn irc_to_discord(message: &str) -> String {
+    render_markdown(&parse_irc(message))
+}
+
+pub(crate) fn irc_to_ansi(message: &str) -> String {
+    ansi::render_ansi(&parse_irc(message))
+}
+
+/// Removes all IRC formatting and colour codes from a message.
+pub(crate) fn strip_irc(message: &str) -> String {
+    parse_irc(message)
+        .into_iter()
+        .map(|span| span.text)
+        .collect()
+}
@@ -0,0 +1,176 @@
+use super::{discord_to_irc, irc_to_ansi, irc_to_discord, strip_irc};
+
+/// Discord markdown and the IRC text it should be relayed as.
+const DISCORD_TO_IRC: &[(&str, &str)] = &[
+    ("plain text", "plain text"),
+    ("**bold**", "\x02bold\x02"),
+    ("*italic*", "\x1Ditalic\x1D"),
+    ("_italic_", "\x1Ditalic\x1D"),
+    ("__underline__", "\x1Funderline\x1F"),
+    ("~~strike~~", "\x1Estrike\x1E"),
+    ("***bold italic***", "\x02\x1Dbold italic\x1D\x02"),
+    (
+        "**bold *and italic* text**",
+        "\x02bold \x1Dand italic\x1D text\x02",
+    ),
+    (
+        "*italic **and bold** text*",
+        "\x1Ditalic \x02and bold\x02 text\x1D",
+    ),
+    ("__*underline italic*__", "\x1D\x1Funderline italic\x1F\x1D"),
+    ("**a** and **b**", "\x02a\x02 and \x02b\x02"),
+    ("**unclosed bold", "**unclosed bold"),
+    ("*unclosed **bold**", "*unclosed \x02bold\x02"),
+    (
+        "**overlapping ~~markup** here~~",
+        "\x02overlapping ~~markup\x02 here~~",
+    ),
+    ("snake_case_name", "snake_case_name"),
+    ("some_var and other_var", "some_var and other_var"),
+    ("2 * 3 * 4", "2 * 3 * 4"),
+    ("~single tilde~", "~single tilde~"),
+    (
+        "https://example.com/some_path_with_underscores",
+        "https://example.com/some_path_with_underscores",
+    ),
+    (
+        "see https://example.com/a_b_c and _this_",
+        "see https://example.com/a_b_c and \x1Dthis\x1D",
+    ),
+    ("<https://example.com/*x*>", "https://example.com/*x*"),
+    ("`code`", "\x11code\x11"),
+    ("`**not bold**`", "\x11**not bold**\x11"),
+    ("``code with ` tick``", "\x11code with ` tick\x11"),
+    ("**bold `code`**", "\x02bold \x11code\x11\x02"),
+    ("`unclosed code", "`unclosed code"),
+    ("` `", "\x11 \x11"),
+    ("`  `", "\x11  \x11"),
+    ("` padded `", "\x11padded\x11"),
+    ("```\nlet a = 1;\n```", "\x11let a = 1;\x11"),
+    (
+        "```rust\nfn main() {}\nlet x_y = 1;\n```",
+        "\x11fn main() {}\x11\n\x11let x_y = 1;\x11",
+    ),
+    ("\\*not italic\\*", "*not italic*"),
+    ("\\_\\_not underline\\_\\_", "__not underline__"),
+    ("back\\\\slash", "back\\slash"),
+    ("<:dnb:123456789>", ":dnb:"),
+    ("<a:spin:987654321> wow", ":spin: wow"),
+    ("**multi\nline**", "\x02multi\x02\n\x02line\x02"),
+];
+
+/// IRC text and the Discord markdown it should be relayed as.
+const IRC_TO_DISCORD: &[(&str, &str)] = &[
+    ("plain text", "plain text"),
+    ("\x02bold\x02", "**bold**"),
+    ("\x02unterminated bold", "**unterminated bold**"),
+    ("\x1Ditalic\x1D", "*italic*"),
+    ("\x1Funderline\x1F", "__underline__"),
+    ("\x1Estrike\x1E", "~~strike~~"),
+    ("\x11code\x11", "`code`"),
+    ("\x11has ` tick\x11", "`` has ` tick ``"),
+    (
+        "\x02bold \x1Dboth\x02 italic\x1D",
+        "**bold *both*** *italic*",
+    ),
+    ("\x02bold \x1Dboth\x0F plain", "**bold *both*** plain"),
+    ("\x0304red\x03 text", "red text"),
+    ("\x0304,12red on blue\x03", "red on blue"),
+    ("\x0304,12\x02bold colour", "**bold colour**"),
+    ("\x03 bare colour code", " bare colour code"),
+    ("\x04ff0000hex\x04", "hex"),
+    ("\x16reversed\x16", "reversed"),
+    ("\x02 padded \x02", " **padded** "),
+    (
+        "snake_case *stars* ~tilde~ |pipe|",
+        "snake\\_case \\*stars\\* \\~tilde\\~ \\|pipe\\|",
+    ),
+    ("\x02under_score\x02", "**under\\_score**"),
+    ("back\\slash `tick`", "back\\\\slash \\`tick\\`"),
+    (
+        "see https://example.com/some_page_x",
+        "see https://example.com/some_page_x",
+    ),
+    (
+        "\x02https://example.com/a_b\x02 and a_b",
+        "**https://example.com/a_b** and a\\_b",
+    ),
+];
+
+/// IRC text and the ANSI code block content it should be rendered as.
+const IRC_TO_ANSI: &[(&str, &str)] = &[
+    ("plain", "plain"),
+    ("\x0304red\x03 plain", "\x1b[0;31mred\x1b[0m plain"),
+    (
+        "\x0308,02yellow on blue",
+        "\x1b[0;33;40myellow on blue\x1b[0m",
+    ),
+    ("\x02\x0312bold blue", "\x1b[0;1;34mbold blue\x1b[0m"),
+    ("\x0399unknown\x0342,42extended", "unknownextended"),
+    ("\x16reversed", "\x1b[0;30;47mreversed\x1b[0m"),
+    ("\x0304```", "\x1b[0;31m`\u{200b}``\x1b[0m"),
+];
+
+/// IRC text that should survive a round trip through Discord markdown unchanged.
+const IRC_ROUND_TRIP: &[&str] = &[
+    "plain",
+    "\x02bold\x02 and \x1Ditalic\x1D",
+    "\x02\x1Dbold italic\x1D\x02",
+    "\x1Fsnake_case\x1F",
+    "\x11code_with*stars\x11",
+    "a *literal* asterisk",
+];
+
+#[test]
+fn discord_to_irc_golden() {
+    for (input, expected) in DISCORD_TO_IRC {
+        assert_eq!(discord_to_irc(input), *expected, "input: {input:?}");
+    }
+}
+
+#[test]
+fn irc_to_discord_golden() {
+    for (input, expected) in IRC_TO_DISCORD {
+        assert_eq!(irc_to_discord(input), *expected, "input: {input:?}");
+    }
+}
+
+#[test]
+fn irc_to_ansi_golden() {
+    for (input, expected) in IRC_TO_ANSI {
+        assert_eq!(irc_to_ansi(input), *expected, "input: {input:?}");
+    }
+}
+
+#[test]
+fn irc_round_trip() {
+    for input in IRC_ROUND_TRIP {
+        assert_eq!(
+            discord_to_irc(&irc_to_discord(input)),
+            *input,
+            "input: {input:?}"
+        );
+    }
+}
+
+#[test]
+fn irc_rendering_keeps_digits_out_of_colour_codes() {
+    use super::{parse_irc, render_irc};
+    for input in [
+        "\x0304red\x0f42",
+        "\x0304,02both\x0304,05",
+        "\x0304red\x03,5",
+        "\x0304red,",
+    ] {
+        let spans = parse_irc(input);
+        assert_eq!(parse_irc(&render_irc(&spans)), spans, "input: {input:?}");
+    }
+}
+
+#[test]
+fn strip_irc_removes_codes() {
+    assert_eq!(
+        strip_irc("\x02\x0304,12Now\x0F playing: \x1Dtrack\x1D"),
+        "Now playing: track"
+    );
+}
@@ -0,0 +1,176 @@
+use crate::context::Context;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use log::error;
+use plotters::prelude::*;
+use plotters::style::{register_font, FontStyle};
+use serenity::all::ChannelId;
+use std::collections::VecDeque;
+use std::sync::{Once, PoisonError};
+
+const GRAPH_WIDTH: u32 = 1000;
+const GRAPH_HEIGHT: u32 = 500;
+const MAX_FINISHED_SHOWS: usize = 10;
+
+static FONT: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+static REGISTER_FONT: Once = Once::new();
+
+#[derive(Clone)]
+pub(crate) struct ShowListeners {
+    pub(crate) show: String,
+    pub(crate) samples: Vec<(DateTime<Utc>, u64)>,
+}
+
+#[derive(Default)]
+pub(crate) struct ListenerGraphs {
+    pub(crate) current: Option<ShowListeners>,
+    pub(crate) finished: VecDeque<ShowListeners>,
+}
+
+impl ShowListeners {
+    pub(crate) fn peak(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn average(&self) -> u64 {
+        if self.samples.is_empty() {
+            return 0;
+        }
+        self.samples.iter().map(|(_, count)| *count).sum::<u64>() / self.samples.len() as u64
+    }
+
+    pub(crate) fn duration(&self) -> chrono::Duration {
+        match (self.samples.first(), self.samples.last()) {
+            (Some((start, _)), Some((end, _))) => *end - *start,
+            _ => chrono::Duration::zero(),
+        }
+    }
+
+    pub(crate) fn summary(&self) -> String {
+        let minutes = self.duration().num_minutes();
+        format!(
+            "Listeners for {}: peak {}, average {} over {}:{:02}",
+            self.show,
+            self.peak(),
+            self.average(),
+            minutes / 60,
+            minutes % 60
+        )
+    }
+}
+
+/// Records a listener sample for the current live show. Returns the finished show once the
+/// stream stops being live or another DJ takes over, so the caller can post its graph.
+pub(crate) fn record_listeners(
+    context: &Context,
+    is_live: bool,
+    show: &str,
+    listeners: u64,
+) -> Option<ShowListeners> {
+    let mut graphs = context
+        .listener_graphs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let finished = graphs
+        .current
+        .take_if(|current| !is_live || current.show != show);
+    if let Some(finished) = &finished {
+        graphs.finished.push_back(finished.clone());
+        if graphs.finished.len() > MAX_FINISHED_SHOWS {
+            graphs.finished.pop_front();
+        }
+    }
+    if is_live {
+        graphs
+            .current
+            .get_or_insert_with(|| ShowListeners {
+                show: show.to_string(),
+                samples: Vec::new(),
+            })
+            .samples
+            .push((Utc::now(), listeners));
+    }
+    finished
+}
+
+/// Finds a show by (partial) name, or the current/most recent show if no name is given.
+pub(crate) fn find_show(context: &Context, show: Option<&str>) -> Option<ShowListeners> {
+    let graphs = context
+        .listener_graphs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let mut shows = graphs.current.iter().chain(graphs.finished.iter().rev());
+    match show {
+        Some(show) => {
+            let show = show.to_lowercase();
+            shows
+                .find(|listeners| listeners.show.to_lowercase().contains(&show))
+                .cloned()
+        }
+        None => shows.next().cloned(),
+    }
+}
+
+pub(crate) async fn post_graph(context: &Context, show: &ShowListeners, channel: &ChannelId) {
+    match render_png(show) {
+        Ok(png) => {
+            context
+                .send_file_to_discord_channel(&show.summary(), "listeners.png", png, channel)
+                .await
+        }
+        Err(error) => error!("Could not render listener graph: {:?}", error),
+    }
+}
+
+pub(crate) fn render_png(show: &ShowListeners) -> Result<Vec<u8>> {
+    if show.samples.len() < 2 {
+        return Err(anyhow!("Not enough listener samples for {}", show.show));
+    }
+    REGISTER_FONT.call_once(|| {
+        if register_font("sans-serif", FontStyle::Normal, FONT).is_err() {
+            error!("Could not register graph font");
+        }
+    });
+
+    let start = show.samples.first().unwrap().0;
+    let end = show.samples.last().unwrap().0;
+    let peak = show.peak();
+
+    let mut buffer = vec![0u8; (GRAPH_WIDTH * GRAPH_HEIGHT * 3) as usize];
+    {
+        let root = BitMapBackend::with_buffer(&mut buffer, (GRAPH_WIDTH, GRAPH_HEIGHT))
+            .into_drawing_area();
+        root.fill(&WHITE)?;
+        let mut chart = ChartBuilder::on(&root)
+            .caption(
+                format!("{} - {}", show.show, start.format("%Y-%m-%d")),
+                ("sans-serif", 28),
+            )
+            .margin(15)
+            .x_label_area_size(40)
+            .y_label_area_size(50)
+            .build_cartesian_2d(start..end, 0u64..peak + peak / 10 + 1)?;
+        chart
+            .configure_mesh()
+            .x_labels(8)
+            .x_label_formatter(&|time| time.format("%H:%M UTC").to_string())
+            .y_desc("Listeners")
+            .draw()?;
+        chart.draw_series(LineSeries::new(
+            show.samples.iter().copied(),
+            RGBColor(0xd9, 0x2b, 0x2b).stroke_width(2),
+        ))?;
+        root.present()?;
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, GRAPH_WIDTH, GRAPH_HEIGHT);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&buffer)?;
+    Ok(png)
+}
@@ -1,13 +1,28 @@
+use crate::accounts::ChatAccount;
+use crate::ansi_relay;
+use crate::avatars;
 use crate::commands;
+use crate::commands::Network;
 use crate::context::Context;
+use crate::irc_caps;
+use crate::irc_caps::CapNegotiation;
+use crate::irc_link;
+use crate::irc_link::Backoff;
+use crate::irc_nick;
+use crate::lag;
+use crate::presence;
+use crate::presence::PresenceEvent;
+use crate::topic;
 use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
 use futures::StreamExt;
 use irc::client::prelude::*;
 use log::{debug, error, warn};
-use serenity::all::{Cache, ChannelId, Http};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
+use tokio::sync::mpsc;
 
 pub(crate) trait IrcClientExt {
     async fn start(self, context: Context);
@@ -16,78 +31,313 @@ pub(crate) trait IrcClientExt {
 
 impl IrcClientExt for Client {
     async fn start(mut self, mut context: Context) {
+        let mut backoff = Backoff::from_env();
         loop {
             if let Err(error) = self.message_loop(&mut context).await {
                 error!("Error in message loop: {:?}", error);
             }
-            error!("IRC client disconnected, reconnecting in 10 seconds");
-            tokio::time::sleep(Duration::from_secs(10)).await;
-            self = match get_irc_client().await {
-                Ok(client) => client,
-                Err(error) => {
-                    error!("Error reconnecting to IRC: {:?}", error);
-                    continue;
+            if irc_link::mark_down(&context).await {
+                backoff.reset();
+            }
+            loop {
+                if context.shutting_down.load(Ordering::Acquire) {
+                    return;
                 }
-            };
-            let mut irc_sender = context.irc_sender.write().unwrap();
+                let delay = backoff.next_delay();
+                error!(
+                    "IRC client disconnected, reconnecting in {:.1} seconds",
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+                match get_irc_client().await {
+                    Ok(client) => {
+                        self = client;
+                        break;
+                    }
+                    Err(error) => error!("Error reconnecting to IRC: {:?}", error),
+                }
+            }
+            let mut irc_sender = context
+                .irc_sender
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
             *irc_sender = self.sender();
         }
     }
 
     async fn message_loop(&mut self, context: &mut Context) -> Result<()> {
         let mut stream = self.stream()?;
-        while let Some(message) = stream.next().await.transpose()? {
+        let mut negotiation = CapNegotiation::default();
+        // Messages for the perform script while it runs.
+        let mut perform: Option<mpsc::UnboundedSender<Message>> = None;
+        // How many alternate nicks we tried while registering.
+        let mut alt_nicks_tried = 0;
+        context
+            .irc_caps
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        context
+            .irc_accounts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        context
+            .irc_nickname
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        context
+            .irc_lag
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .reset();
+        let lag_interval = context
+            .irc_lag
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .interval;
+        // The lag check's PONGs arrive even when the channel is quiet.
+        context.metrics.watch("irc", lag_interval);
+        let mut lag_check = tokio::time::interval(lag_interval);
+        loop {
+            let message = tokio::select! {
+                message = stream.next() => message,
+                _ = lag_check.tick() => {
+                    lag::check(self, context)?;
+                    continue;
+                }
+            };
+            let message = match message.transpose() {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(irc::error::Error::NoUsableNick) => {
+                    irc_nick::handle_nickname_in_use(context, self, &mut alt_nicks_tried)?;
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
             debug!("{:?}", message);
+            context.metrics.heartbeat("irc");
             let nickname = message.source_nickname().unwrap_or("Unknown");
+            track_account(context, &message);
+            if let Some(sender) = &perform {
+                if sender.send(message.clone()).is_err() {
+                    perform = None;
+                }
+            }
             match message.command {
+                Command::CAP(_, ref subcommand, ref first, ref second) => {
+                    irc_caps::handle_cap(
+                        self,
+                        context,
+                        &mut negotiation,
+                        subcommand,
+                        first.as_deref(),
+                        second.as_deref(),
+                    )?;
+                }
+                Command::PONG(ref server, ref token) => {
+                    lag::handle_pong(context, token.as_deref().unwrap_or(server));
+                }
+                Command::AUTHENTICATE(ref data) => {
+                    irc_caps::handle_authenticate(self, context, data)?;
+                }
+                Command::Response(Response::RPL_SASLSUCCESS, _) => {
+                    irc_caps::handle_sasl_result(self, &mut negotiation, true)?;
+                }
+                Command::Response(Response::ERR_SASLFAIL, _)
+                | Command::Response(Response::ERR_SASLTOOLONG, _)
+                | Command::Response(Response::ERR_SASLABORT, _)
+                | Command::Response(Response::ERR_SASLALREADY, _) => {
+                    irc_caps::handle_sasl_result(self, &mut negotiation, false)?;
+                }
+                Command::Response(Response::RPL_WELCOME, ref args) => {
+                    if let Some(nickname) = args.first() {
+                        irc_nick::handle_welcome(context, nickname);
+                    }
+                }
+                Command::Response(Response::RPL_ISON, ref args) => {
+                    irc_nick::handle_ison(context, self, args.last().map_or("", String::as_str));
+                }
                 Command::Response(Response::RPL_ENDOFMOTD, _)
                 | Command::Response(Response::ERR_NOMOTD, _) => {
-                    let perform = env::var("IRC_PERFORM").unwrap_or("".to_owned());
-                    if !perform.is_empty() {
-                        let mut command_parts = perform.split(' ');
-                        let command_name = command_parts.next().unwrap_or("");
-                        let command_args = command_parts.map(ToOwned::to_owned).collect();
-                        if let Err(err) =
-                            self.send(Command::Raw(command_name.to_owned(), command_args))
-                        {
-                            error!("Error sending perform: {}", err);
-                        }
-                    }
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    perform = Some(sender);
+                    tokio::spawn(context.irc_perform.clone().run(self.sender(), receiver));
                 }
                 Command::PRIVMSG(ref target, ref msg) => {
+                    let private = irc_nick::is_current_nickname(context, target);
+                    if commands::is_link_command(context, msg)
+                        && (private || target == &context.irc_channel)
+                    {
+                        // Link codes stay off the relay, and the reply only goes to the sender.
+                        let account = get_account(context, &message).map(|account| ChatAccount {
+                            network: Network::Irc,
+                            account,
+                        });
+                        let reply =
+                            commands::handle_link(context, nickname, account, &msg[1..], private)
+                                .await;
+                        let reply_target = if private { nickname } else { target };
+                        context
+                            .send_to_irc_channel(&reply, reply_target, None)
+                            .await;
+                        continue;
+                    }
                     if target != &context.irc_channel {
                         continue;
                     }
-                    let avatar_url = get_avatar_url(
-                        context.discord_channel,
-                        &context.discord_http,
-                        &context.discord_cache,
+                    let avatar_url = avatars::get_avatar_url(context, nickname);
+                    let relayed = match irc_caps::get_server_time(&message) {
+                        Some(time) if Utc::now() - time > MAX_RELAY_DELAY => {
+                            with_timestamp(msg, time)
+                        }
+                        _ => msg.clone(),
+                    };
+                    ansi_relay::relay(
+                        context,
                         nickname,
+                        &relayed,
+                        avatar_url,
+                        irc_caps::get_tag(&message, "msgid"),
                     )
                     .await;
                     context
-                        .send_to_discord_webhook_relay(nickname, msg, avatar_url)
-                        .await;
+                        .metrics
+                        .relayed_irc_to_discord
+                        .fetch_add(1, Ordering::Relaxed);
                     if msg.starts_with(&context.command_prefix) {
                         let command = &msg[1..];
-                        if let Err(error) =
-                            commands::handle_command(context, target, nickname, command, false)
-                                .await
+                        if let Err(error) = commands::handle_command(
+                            context,
+                            Network::Irc,
+                            target,
+                            nickname,
+                            command,
+                            is_admin(context, &message),
+                            get_account(context, &message).map(|account| ChatAccount {
+                                network: Network::Irc,
+                                account,
+                            }),
+                        )
+                        .await
                         {
                             warn!("Error handling command {}: {:?}", command, error);
                         }
                     }
                 }
-                Command::JOIN(ref channel, _, _) => {
-                    if channel == &context.irc_channel {
-                        // Voice user.
-                        if let Err(error) = self.send(Command::ChannelMODE(
-                            context.irc_channel.to_string(),
-                            vec![Mode::Plus(ChannelMode::Voice, Some(nickname.to_string()))],
-                        )) {
-                            warn!("Error setting voice mode: {:?}", error);
+                Command::TOPIC(ref channel, Some(ref topic))
+                    if channel == &context.irc_channel
+                        && !irc_nick::is_current_nickname(context, nickname) =>
+                {
+                    topic::mirror_irc_topic(context, topic).await;
+                }
+                Command::Response(Response::RPL_NAMREPLY, ref args) => {
+                    if args.get(2) != Some(&context.irc_channel) {
+                        continue;
+                    }
+                    if let Some(names) = args.last() {
+                        let mut irc_names = context
+                            .irc_names
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        for name in names.split_whitespace() {
+                            irc_names.insert(
+                                name.trim_start_matches(['~', '&', '@', '%', '+'])
+                                    .to_owned(),
+                            );
+                        }
+                    }
+                }
+                Command::JOIN(ref channel, _, _) if channel == &context.irc_channel => {
+                    // Voice user.
+                    if let Err(error) = self.send(Command::ChannelMODE(
+                        context.irc_channel.to_string(),
+                        vec![Mode::Plus(ChannelMode::Voice, Some(nickname.to_string()))],
+                    )) {
+                        warn!("Error setting voice mode: {:?}", error);
+                    }
+                    if irc_nick::is_current_nickname(context, nickname) {
+                        // The NAMES reply after our own join repopulates the list.
+                        context
+                            .irc_names
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .clear();
+                        irc_link::mark_up(context).await;
+                        continue;
+                    }
+                    context
+                        .irc_names
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .insert(nickname.to_owned());
+                    presence::relay(context, PresenceEvent::Join(nickname.to_owned())).await;
+                }
+                Command::PART(ref channel, ref reason) if channel == &context.irc_channel => {
+                    context
+                        .irc_names
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(nickname);
+                    presence::relay(
+                        context,
+                        PresenceEvent::Part(nickname.to_owned(), reason.clone()),
+                    )
+                    .await;
+                }
+                Command::KICK(ref channel, ref kicked, ref reason)
+                    if channel == &context.irc_channel =>
+                {
+                    context
+                        .irc_names
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(kicked);
+                    presence::relay(
+                        context,
+                        PresenceEvent::Kick(kicked.clone(), nickname.to_owned(), reason.clone()),
+                    )
+                    .await;
+                }
+                Command::QUIT(ref reason) => {
+                    irc_nick::handle_nickname_gone(context, self, nickname);
+                    // QUITs aren't tied to a channel, so only relay users we saw in ours.
+                    if !context
+                        .irc_names
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .remove(nickname)
+                    {
+                        continue;
+                    }
+                    presence::relay(
+                        context,
+                        PresenceEvent::Quit(nickname.to_owned(), reason.clone()),
+                    )
+                    .await;
+                }
+                Command::NICK(ref new_nickname) => {
+                    let own_nick = irc_nick::handle_nick(context, self, nickname, new_nickname);
+                    {
+                        let mut irc_names = context
+                            .irc_names
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        if !irc_names.remove(nickname) {
+                            continue;
                         }
+                        irc_names.insert(new_nickname.clone());
                     }
+                    if own_nick {
+                        continue;
+                    }
+                    presence::relay(
+                        context,
+                        PresenceEvent::Nick(nickname.to_owned(), new_nickname.clone()),
+                    )
+                    .await;
                 }
                 _ => {}
             }
@@ -96,13 +346,218 @@ impl IrcClientExt for Client {
     }
 }
 
+/// Matches an IRC hostmask against a pattern where `*` matches any run of characters and `?` any
+/// single character.
+pub(crate) fn hostmask_matches(pattern: &str, hostmask: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let hostmask = hostmask.to_lowercase().chars().collect::<Vec<_>>();
+    let (mut p, mut h) = (0, 0);
+    let mut backtrack = None;
+    while h < hostmask.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, h));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == hostmask[h] => {
+                p += 1;
+                h += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    h = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Messages that reach us later than this, like after a netsplit, are relayed with the time they
+/// were sent.
+const MAX_RELAY_DELAY: TimeDelta = TimeDelta::seconds(60);
+
+fn with_timestamp(message: &str, time: DateTime<Utc>) -> String {
+    let timestamp = time.format("[%H:%M UTC]");
+    match message.strip_prefix("\x01ACTION ") {
+        Some(action) => format!("\x01ACTION {} {}", timestamp, action),
+        None => format!("{} {}", timestamp, message),
+    }
+}
+
+/// A services account we learned about, and the channels we share with its user.
+pub(crate) struct TrackedAccount {
+    account: String,
+    channels: HashSet<String>,
+}
+
+/// Tracked accounts keyed by lowercase nickname.
+pub(crate) type IrcAccounts = HashMap<String, TrackedAccount>;
+
+/// Keeps track of which services account everyone is logged in to, through `extended-join` and
+/// `account-notify`, for servers that don't tag messages with the account. Once we share no
+/// channel with a user we stop seeing their QUIT, so they're forgotten when they leave the last one.
+fn track_account(context: &Context, message: &Message) {
+    let Some(nickname) = message.source_nickname() else {
+        return;
+    };
+    let nickname = nickname.to_lowercase();
+    let mut accounts = context
+        .irc_accounts
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    match &message.command {
+        Command::JOIN(channel, account, real_name) => {
+            let extended_join = context
+                .irc_caps
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .contains("extended-join");
+            let channel = channel.to_lowercase();
+            match account
+                .as_deref()
+                .filter(|_| extended_join && real_name.is_some())
+            {
+                Some("*") => {
+                    accounts.remove(&nickname);
+                }
+                Some(account) => {
+                    let tracked = accounts.entry(nickname).or_insert_with(|| TrackedAccount {
+                        account: account.to_owned(),
+                        channels: HashSet::new(),
+                    });
+                    tracked.account = account.to_owned();
+                    tracked.channels.insert(channel);
+                }
+                None => {
+                    if let Some(tracked) = accounts.get_mut(&nickname) {
+                        tracked.channels.insert(channel);
+                    }
+                }
+            }
+        }
+        Command::ACCOUNT(account) if account == "*" => {
+            accounts.remove(&nickname);
+        }
+        Command::ACCOUNT(account) => {
+            accounts
+                .entry(nickname)
+                .or_insert_with(|| TrackedAccount {
+                    account: account.clone(),
+                    channels: HashSet::new(),
+                })
+                .account = account.clone();
+        }
+        Command::PART(channel, _) => leave_channel(context, &mut accounts, &nickname, channel),
+        Command::KICK(channel, kicked, _) => {
+            leave_channel(context, &mut accounts, &kicked.to_lowercase(), channel)
+        }
+        Command::NICK(new_nickname) => {
+            if let Some(tracked) = accounts.remove(&nickname) {
+                accounts.insert(new_nickname.to_lowercase(), tracked);
+            }
+        }
+        Command::QUIT(_) => {
+            accounts.remove(&nickname);
+        }
+        _ => {}
+    }
+}
+
+/// Forgets `nickname`'s account once they left the last channel we know we share. If it's us
+/// leaving, that goes for everyone in the channel.
+fn leave_channel(context: &Context, accounts: &mut IrcAccounts, nickname: &str, channel: &str) {
+    let channel = channel.to_lowercase();
+    if irc_nick::is_current_nickname(context, nickname) {
+        accounts.retain(|_, tracked| {
+            tracked.channels.remove(&channel);
+            !tracked.channels.is_empty()
+        });
+        return;
+    }
+    if let Some(tracked) = accounts.get_mut(nickname) {
+        tracked.channels.remove(&channel);
+        if tracked.channels.is_empty() {
+            accounts.remove(nickname);
+        }
+    }
+}
+
+/// The services account the sender is logged in to, from the IRCv3 `account` message tag or, if
+/// the server doesn't tag messages, what we've tracked for their nick.
+fn get_account(context: &Context, message: &Message) -> Option<String> {
+    let tagged = irc_caps::get_tag(message, "account")
+        .filter(|account| !account.is_empty() && *account != "*")
+        .map(ToOwned::to_owned);
+    // With account-tag, an untagged message means the sender isn't logged in.
+    if context
+        .irc_caps
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains("account-tag")
+    {
+        return tagged;
+    }
+    tagged.or_else(|| {
+        let nickname = message.source_nickname()?.to_lowercase();
+        context
+            .irc_accounts
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&nickname)
+            .map(|tracked| tracked.account.clone())
+    })
+}
+
+/// Whether a message comes from one of the hostmasks in `IRC_ADMINS`, or from a services account
+/// listed there as `$a:account`.
+fn is_admin(context: &Context, message: &Message) -> bool {
+    let Some(prefix) = &message.prefix else {
+        return false;
+    };
+    let hostmask = prefix.to_string();
+    let account = get_account(context, message);
+    context
+        .irc_admins
+        .iter()
+        .any(|pattern| match pattern.strip_prefix("$a:") {
+            Some(admin_account) => account
+                .as_ref()
+                .is_some_and(|account| account.eq_ignore_ascii_case(admin_account)),
+            None => hostmask_matches(pattern, &hostmask),
+        })
+}
+
+/// Starts over with a new connection after the IRC task crashed.
+pub(crate) async fn reconnect(context: Context) {
+    irc_link::mark_down(&context).await;
+    let mut backoff = Backoff::from_env();
+    let client = loop {
+        if context.shutting_down.load(Ordering::Acquire) {
+            return;
+        }
+        match get_irc_client().await {
+            Ok(client) => break client,
+            Err(error) => {
+                error!("Error reconnecting to IRC: {:?}", error);
+                tokio::time::sleep(backoff.next_delay()).await;
+            }
+        }
+    };
+    *context
+        .irc_sender
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = client.sender();
+    client.start(context).await;
+}
+
 pub async fn get_irc_client() -> Result<Client> {
+    let nickname = env::var("IRC_NICK").expect("IRC_NICK must be set");
     let config = Config {
-        nickname: Some(
-            env::var("IRC_NICK")
-                .expect("IRC_NICK must be set")
-                .to_owned(),
-        ),
+        nickname: Some(nickname),
         server: Some(
             env::var("IRC_SERVER")
                 .expect("IRC_SERVER must be set")
@@ -114,11 +569,6 @@ pub async fn get_irc_client() -> Result<Client> {
                 .parse::<u16>()
                 .expect("IRC_PORT must be a number"),
         ),
-        channels: env::var("IRC_CHANNELS")
-            .expect("IRC_CHANNELS must be set")
-            .split(',')
-            .map(|s| s.to_owned())
-            .collect(),
         use_tls: Some(
             env::var("IRC_USE_TLS")
                 .unwrap_or("false".to_owned())
@@ -126,35 +576,17 @@ pub async fn get_irc_client() -> Result<Client> {
                 .expect("IRC_USE_TLS must be true or false"),
         ),
         password: env::var("IRC_PASSWORD").ok(),
+        client_cert_path: env::var("IRC_CLIENT_CERT_PATH")
+            .ok()
+            .filter(|s| !s.is_empty()),
+        client_cert_pass: env::var("IRC_CLIENT_CERT_PASS")
+            .ok()
+            .filter(|s| !s.is_empty()),
         ..Config::default()
     };
 
-    let client = Client::from_config(config).await?;
-    client.identify()?;
+    let client = Client::from_config(config.clone()).await?;
+    irc_caps::register(&client, &config)?;
 
     Ok(client)
 }
-
-pub async fn get_avatar_url(
-    channel_id: ChannelId,
-    http: &Arc<Http>,
-    cache: &Arc<Cache>,
-    nickname: &str,
-) -> Option<String> {
-    if let Ok(channel) = &channel_id.to_channel(&http).await {
-        if let Some(guild) = channel.clone().guild() {
-            if let Ok(members) = guild.members(cache) {
-                for member in members {
-                    if member.display_name() == nickname {
-                        return member.user.avatar_url();
-                    }
-                }
-            } else {
-                error!("Could not get members from guild");
-            }
-        } else {
-            error!("Could not get guild from channel");
-        }
-    }
-    None
-}
@@ -0,0 +1,242 @@
+use crate::context::Context;
+use anyhow::Result;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use irc::client::prelude::*;
+use irc::proto::CapSubCommand;
+use log::{error, info, warn};
+use std::env;
+use std::sync::PoisonError;
+
+/// Capabilities the bot makes use of when the server offers them.
+const WANTED_CAPS: &[&str] = &[
+    "account-notify",
+    "account-tag",
+    "extended-join",
+    "message-tags",
+    "server-time",
+];
+
+/// The longest AUTHENTICATE payload the server accepts in one line.
+const SASL_CHUNK_LENGTH: usize = 400;
+
+#[derive(Debug, Clone)]
+pub(crate) enum SaslMechanism {
+    Plain {
+        username: String,
+        password: String,
+    },
+    /// Authenticates with the TLS client certificate in `IRC_CLIENT_CERT_PATH`.
+    External,
+}
+
+impl SaslMechanism {
+    pub(crate) fn from_env() -> Option<Self> {
+        match env::var("IRC_SASL_MECHANISM")
+            .unwrap_or("none".to_owned())
+            .to_lowercase()
+            .as_str()
+        {
+            "none" | "" => None,
+            "plain" => Some(SaslMechanism::Plain {
+                username: env::var("IRC_SASL_USERNAME")
+                    .ok()
+                    .filter(|s| !s.is_empty())
+                    .unwrap_or_else(|| env::var("IRC_NICK").expect("IRC_NICK must be set")),
+                password: env::var("IRC_SASL_PASSWORD")
+                    .expect("IRC_SASL_PASSWORD must be set for SASL PLAIN"),
+            }),
+            "external" => Some(SaslMechanism::External),
+            _ => panic!("IRC_SASL_MECHANISM must be none, plain or external"),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain { .. } => "PLAIN",
+            SaslMechanism::External => "EXTERNAL",
+        }
+    }
+
+    fn payload(&self) -> String {
+        match self {
+            SaslMechanism::Plain { username, password } => {
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!("{username}\0{username}\0{password}"))
+            }
+            SaslMechanism::External => String::new(),
+        }
+    }
+}
+
+/// Capability negotiation for a single connection.
+#[derive(Default)]
+pub(crate) struct CapNegotiation {
+    offered: Vec<String>,
+    finished: bool,
+}
+
+/// Registers the connection, starting capability negotiation before sending the nickname so SASL
+/// can complete before the server welcomes us.
+pub(crate) fn register(client: &Client, config: &Config) -> Result<()> {
+    client.send(Command::CAP(
+        None,
+        CapSubCommand::LS,
+        Some("302".to_owned()),
+        None,
+    ))?;
+    if !config.password().is_empty() {
+        client.send(Command::PASS(config.password().to_owned()))?;
+    }
+    client.send(Command::NICK(config.nickname()?.to_owned()))?;
+    client.send(Command::USER(
+        config.username().to_owned(),
+        "0".to_owned(),
+        config.real_name().to_owned(),
+    ))?;
+    Ok(())
+}
+
+fn end_negotiation(client: &Client, negotiation: &mut CapNegotiation) -> Result<()> {
+    if !negotiation.finished {
+        negotiation.finished = true;
+        client.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn handle_cap(
+    client: &Client,
+    context: &Context,
+    negotiation: &mut CapNegotiation,
+    subcommand: &CapSubCommand,
+    first: Option<&str>,
+    second: Option<&str>,
+) -> Result<()> {
+    match subcommand {
+        CapSubCommand::LS => {
+            // A `*` before the list means more lines follow.
+            let (more, caps) = match (first, second) {
+                (Some("*"), Some(caps)) => (true, caps),
+                (Some(caps), _) => (false, caps),
+                (None, _) => (false, ""),
+            };
+            negotiation.offered.extend(
+                caps.split_whitespace()
+                    .map(|cap| cap.split('=').next().unwrap_or(cap).to_owned()),
+            );
+            if more {
+                return Ok(());
+            }
+            let mut wanted = WANTED_CAPS
+                .iter()
+                .filter(|cap| negotiation.offered.iter().any(|offered| offered == *cap))
+                .map(|cap| cap.to_string())
+                .collect::<Vec<_>>();
+            if context.irc_sasl.is_some() {
+                if negotiation.offered.iter().any(|offered| offered == "sasl") {
+                    wanted.push("sasl".to_owned());
+                } else {
+                    warn!("IRC server doesn't support SASL, continuing without it");
+                }
+            }
+            if wanted.is_empty() {
+                return end_negotiation(client, negotiation);
+            }
+            client.send(Command::CAP(
+                None,
+                CapSubCommand::REQ,
+                None,
+                Some(wanted.join(" ")),
+            ))?;
+        }
+        CapSubCommand::ACK => {
+            let acked = first.unwrap_or_default();
+            info!("IRC capabilities enabled: {}", acked);
+            context
+                .irc_caps
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .extend(acked.split_whitespace().map(ToOwned::to_owned));
+            match &context.irc_sasl {
+                Some(mechanism) if acked.split_whitespace().any(|cap| cap == "sasl") => {
+                    client.send(Command::AUTHENTICATE(mechanism.name().to_owned()))?;
+                }
+                _ => end_negotiation(client, negotiation)?,
+            }
+        }
+        CapSubCommand::NAK => {
+            warn!(
+                "IRC server refused capabilities: {}",
+                first.unwrap_or_default()
+            );
+            end_negotiation(client, negotiation)?;
+        }
+        CapSubCommand::DEL => {
+            let mut caps = context
+                .irc_caps
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            for cap in first.unwrap_or_default().split_whitespace() {
+                caps.remove(cap);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Answers the server's `AUTHENTICATE +` prompt with our credentials.
+pub(crate) fn handle_authenticate(client: &Client, context: &Context, data: &str) -> Result<()> {
+    let Some(mechanism) = &context.irc_sasl else {
+        return Ok(());
+    };
+    if data != "+" {
+        return Ok(());
+    }
+    let payload = mechanism.payload();
+    if payload.is_empty() {
+        client.send(Command::AUTHENTICATE("+".to_owned()))?;
+        return Ok(());
+    }
+    for chunk in payload.as_bytes().chunks(SASL_CHUNK_LENGTH) {
+        client.send(Command::AUTHENTICATE(
+            String::from_utf8_lossy(chunk).into_owned(),
+        ))?;
+    }
+    // A payload that fills the last chunk exactly has to be terminated explicitly.
+    if payload.len() % SASL_CHUNK_LENGTH == 0 {
+        client.send(Command::AUTHENTICATE("+".to_owned()))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn handle_sasl_result(
+    client: &Client,
+    negotiation: &mut CapNegotiation,
+    success: bool,
+) -> Result<()> {
+    if success {
+        info!("SASL authentication successful");
+    } else {
+        error!("SASL authentication failed, continuing without it");
+    }
+    end_negotiation(client, negotiation)
+}
+
+pub(crate) fn get_tag<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    message
+        .tags
+        .as_ref()?
+        .iter()
+        .find(|tag| tag.0 == name)
+        .and_then(|tag| tag.1.as_deref())
+}
+
+/// When the server says the message was sent, from the `server-time` tag.
+pub(crate) fn get_server_time(message: &Message) -> Option<DateTime<Utc>> {
+    let time = get_tag(message, "time")?;
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
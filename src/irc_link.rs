@@ -0,0 +1,194 @@
+use crate::context::Context;
+use irc::client::prelude::*;
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::env;
+use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+
+/// Whether the IRC link is up, and the relayed Discord lines waiting for it to come back.
+pub(crate) struct IrcLink {
+    connected: bool,
+    down_since: Option<Instant>,
+    buffer: VecDeque<Message>,
+    buffer_size: usize,
+    dropped: usize,
+}
+
+impl IrcLink {
+    pub(crate) fn from_env() -> Self {
+        IrcLink {
+            connected: false,
+            down_since: None,
+            buffer: VecDeque::new(),
+            buffer_size: env::var("IRC_OUTAGE_BUFFER")
+                .unwrap_or("50".to_owned())
+                .parse()
+                .expect("IRC_OUTAGE_BUFFER must be a number"),
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Holds on to a relayed line until the link is back, dropping the oldest once the buffer is
+    /// full.
+    pub(crate) fn buffer(&mut self, message: Message) {
+        if self.buffer_size == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.buffer.len() >= self.buffer_size {
+            self.buffer.pop_front();
+            self.dropped += 1;
+        }
+        self.buffer.push_back(message);
+    }
+}
+
+/// Exponential backoff between reconnect attempts.
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub(crate) fn from_env() -> Self {
+        let seconds = |name: &str, default: &str| {
+            Duration::from_secs(
+                env::var(name)
+                    .unwrap_or(default.to_owned())
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{} must be a number", name)),
+            )
+        };
+        Backoff {
+            min: seconds("IRC_RECONNECT_MIN", "5"),
+            max: seconds("IRC_RECONNECT_MAX", "300"),
+            attempts: 0,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// The delay before the next attempt: doubling from the minimum up to the maximum, with a
+    /// random half of it taken off so clients don't all reconnect at once after a netsplit.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        delay.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+fn format_outage(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+/// Records that the connection dropped, telling Discord if it was up. Returns whether it was.
+pub(crate) async fn mark_down(context: &Context) -> bool {
+    {
+        let mut link = context
+            .irc_link
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if !link.connected {
+            return false;
+        }
+        link.connected = false;
+        link.down_since = Some(Instant::now());
+    }
+    if context.shutting_down.load(Ordering::Acquire) {
+        return true;
+    }
+    warn!("IRC link is down");
+    context
+        .send_to_discord(
+            "⚠️ Lost connection to IRC, reconnecting. Messages sent here will be relayed once it's back.",
+        )
+        .await;
+    true
+}
+
+/// Tells Discord about the lines still waiting for the link when shutting down, as there's no
+/// connection left to send them on.
+pub(crate) async fn discard_buffer(context: &Context) {
+    let discarded = {
+        let mut link = context
+            .irc_link
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let discarded = link.buffer.len() + link.dropped;
+        link.buffer.clear();
+        link.dropped = 0;
+        discarded
+    };
+    if discarded > 0 {
+        context
+            .send_to_discord(&format!(
+                "⚠️ Shutting down before IRC came back, {} lines sent here weren't relayed.",
+                discarded
+            ))
+            .await;
+    }
+}
+
+/// Records that we're back in the main channel, flushing everything buffered in the meantime.
+pub(crate) async fn mark_up(context: &Context) {
+    let (down_since, buffered, dropped) = {
+        let mut link = context
+            .irc_link
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if link.connected {
+            return;
+        }
+        link.connected = true;
+        let buffered = link.buffer.drain(..).collect::<Vec<_>>();
+        (
+            link.down_since.take(),
+            buffered,
+            std::mem::take(&mut link.dropped),
+        )
+    };
+    info!("IRC link is up, relaying {} buffered lines", buffered.len());
+    {
+        let irc_sender = context
+            .irc_sender
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        for message in buffered {
+            if let Err(error) = irc_sender.send(message) {
+                error!("Error sending buffered message to IRC: {:?}", error);
+            }
+        }
+    }
+    // The first connection isn't worth announcing.
+    let Some(down_since) = down_since else {
+        return;
+    };
+    let mut notice = format!(
+        "✅ Reconnected to IRC after {}.",
+        format_outage(down_since.elapsed())
+    );
+    if dropped > 0 {
+        notice.push_str(&format!(
+            " {} lines sent in the meantime were dropped.",
+            dropped
+        ));
+    }
+    context.send_to_discord(&notice).await;
+}
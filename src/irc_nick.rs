@@ -0,0 +1,193 @@
+use crate::context::Context;
+use anyhow::{bail, Result};
+use irc::client::prelude::*;
+use log::{error, info, warn};
+use std::env;
+use std::sync::PoisonError;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How the bot gets its configured nickname back after falling back to an alternate one.
+#[derive(Debug, Clone)]
+pub(crate) struct NickRegain {
+    pub(crate) nickname: String,
+    /// The NickServ command that frees the nickname, like GHOST, RECOVER or REGAIN. Without one
+    /// the bot only takes its nick back once it's free.
+    command: Option<String>,
+    password: String,
+    interval: Duration,
+}
+
+impl NickRegain {
+    pub(crate) fn from_env() -> Self {
+        let password = env::var("IRC_NICKSERV_PASSWORD").unwrap_or("".to_owned());
+        let command = env::var("IRC_NICK_REGAIN_COMMAND")
+            .unwrap_or("GHOST".to_owned())
+            .to_uppercase();
+        NickRegain {
+            nickname: env::var("IRC_NICK").expect("IRC_NICK must be set"),
+            command: Some(command).filter(|command| !command.is_empty() && !password.is_empty()),
+            password,
+            interval: Duration::from_secs(
+                env::var("IRC_NICK_REGAIN_INTERVAL")
+                    .unwrap_or("60".to_owned())
+                    .parse()
+                    .expect("IRC_NICK_REGAIN_INTERVAL must be a number"),
+            ),
+        }
+    }
+}
+
+/// The nicknames to fall back to while registering if `IRC_NICK` is taken.
+pub(crate) fn alternate_nicknames(nickname: &str) -> Vec<String> {
+    match env::var("IRC_ALT_NICKS").ok().filter(|s| !s.is_empty()) {
+        Some(nicknames) => nicknames
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        None => vec![format!("{nickname}_"), format!("{nickname}__")],
+    }
+}
+
+pub(crate) fn current_nickname(context: &Context) -> String {
+    context
+        .irc_nickname
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+pub(crate) fn is_current_nickname(context: &Context, nickname: &str) -> bool {
+    context
+        .irc_nickname
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .eq_ignore_ascii_case(nickname)
+}
+
+fn has_nickname(context: &Context) -> bool {
+    is_current_nickname(context, &context.irc_nick_regain.nickname)
+}
+
+/// Handles the server refusing a nickname, which the `irc` crate reports as
+/// [`irc::error::Error::NoUsableNick`] as it has no alternate nicks configured. While registering
+/// this moves on to the next of [`alternate_nicknames`], counting in `tried`, and gives up once
+/// they're all taken. Once registered it's a regain attempt that failed, so we keep our nick.
+pub(crate) fn handle_nickname_in_use(
+    context: &Context,
+    client: &Client,
+    tried: &mut usize,
+) -> Result<()> {
+    if !current_nickname(context).is_empty() {
+        warn!(
+            "IRC nick {} is still in use, keeping {}",
+            context.irc_nick_regain.nickname,
+            current_nickname(context)
+        );
+        return Ok(());
+    }
+    let alternates = alternate_nicknames(&context.irc_nick_regain.nickname);
+    let Some(nickname) = alternates.get(*tried) else {
+        bail!("All IRC nicknames are in use");
+    };
+    *tried += 1;
+    client.send(Command::NICK(nickname.clone()))?;
+    Ok(())
+}
+
+/// Records the nickname the server registered us under.
+pub(crate) fn handle_welcome(context: &Context, nickname: &str) {
+    *context
+        .irc_nickname
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = nickname.to_owned();
+    if !has_nickname(context) {
+        warn!(
+            "IRC nick {} is taken, using {} until it frees up",
+            context.irc_nick_regain.nickname, nickname
+        );
+    }
+}
+
+/// Follows nick changes. Returns whether the change was our own.
+pub(crate) fn handle_nick(context: &Context, client: &Client, old: &str, new: &str) -> bool {
+    if is_current_nickname(context, old) {
+        info!("IRC nick changed from {} to {}", old, new);
+        *context
+            .irc_nickname
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = new.to_owned();
+        return true;
+    }
+    handle_nickname_gone(context, client, old);
+    false
+}
+
+/// Takes our nickname as soon as whoever had it quits or changes away from it.
+pub(crate) fn handle_nickname_gone(context: &Context, client: &Client, nickname: &str) {
+    if has_nickname(context) || !nickname.eq_ignore_ascii_case(&context.irc_nick_regain.nickname) {
+        return;
+    }
+    if let Err(error) = client.send(Command::NICK(context.irc_nick_regain.nickname.clone())) {
+        error!("Error regaining IRC nick: {:?}", error);
+    }
+}
+
+/// Handles the ISON reply to [`start`]'s check: takes the nick if it's free, or asks NickServ to
+/// free it up and then takes it.
+pub(crate) fn handle_ison(context: &Context, client: &Client, online: &str) {
+    let regain = &context.irc_nick_regain;
+    if has_nickname(context) {
+        return;
+    }
+    let taken = online
+        .split_whitespace()
+        .any(|nickname| nickname.eq_ignore_ascii_case(&regain.nickname));
+    if taken {
+        let Some(command) = &regain.command else {
+            return;
+        };
+        info!("Asking NickServ to {} {}", command, regain.nickname);
+        if let Err(error) = client.send(Command::PRIVMSG(
+            "NickServ".to_owned(),
+            format!("{} {} {}", command, regain.nickname, regain.password),
+        )) {
+            error!("Error regaining IRC nick: {:?}", error);
+            return;
+        }
+    }
+    // REGAIN changes our nick itself, which makes this a no-op. If GHOST hasn't freed the nick
+    // yet, the next check tries again.
+    if let Err(error) = client.send(Command::NICK(regain.nickname.clone())) {
+        error!("Error regaining IRC nick: {:?}", error);
+    }
+}
+
+/// Periodically checks whether the configured nick is free while running under another one.
+pub(crate) async fn start(context: Context) {
+    info!("Starting IRC nick regain loop");
+    context
+        .metrics
+        .watch("irc_nick", context.irc_nick_regain.interval);
+    loop {
+        sleep(context.irc_nick_regain.interval).await;
+        context.metrics.heartbeat("irc_nick");
+        if has_nickname(&context) || current_nickname(&context).is_empty() {
+            continue;
+        }
+        let irc_sender = context
+            .irc_sender
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if let Err(error) = irc_sender.send(Command::ISON(vec![context
+            .irc_nick_regain
+            .nickname
+            .clone()]))
+        {
+            error!("Error checking IRC nick: {:?}", error);
+        }
+    }
+}
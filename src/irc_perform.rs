@@ -0,0 +1,175 @@
+use crate::irc::hostmask_matches;
+use anyhow::{anyhow, Result};
+use irc::client::prelude::*;
+use log::{error, info};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{sleep, timeout_at, Instant};
+
+/// Something the server has to send before the script carries on.
+#[derive(Debug)]
+enum Expectation {
+    /// A numeric reply, like `900` for RPL_LOGGEDIN.
+    Numeric(u16),
+    /// A NOTICE from `nickname` whose text matches the glob `text`.
+    Notice { nickname: String, text: String },
+}
+
+impl Expectation {
+    fn matches(&self, message: &Message) -> bool {
+        match (self, &message.command) {
+            (Expectation::Numeric(numeric), Command::Response(response, _)) => {
+                *response as u16 == *numeric
+            }
+            (Expectation::Numeric(numeric), Command::Raw(command, _)) => {
+                command.parse::<u16>().ok() == Some(*numeric)
+            }
+            (Expectation::Notice { nickname, text }, Command::NOTICE(_, notice)) => {
+                message
+                    .source_nickname()
+                    .is_some_and(|source| source.eq_ignore_ascii_case(nickname))
+                    && hostmask_matches(text, notice)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PerformStep {
+    Send(Message),
+    Sleep(Duration),
+    Wait(Expectation),
+}
+
+impl PerformStep {
+    /// Parses one step of `IRC_PERFORM`:
+    ///
+    /// - `sleep <seconds>` pauses the script
+    /// - `wait <numeric>` waits for a numeric reply
+    /// - `wait notice <nick> [text]` waits for a NOTICE from `nick`, optionally matching the glob
+    ///   `text`
+    /// - anything else is sent as a raw IRC line, like `PRIVMSG NickServ :IDENTIFY password`
+    fn parse(step: &str) -> Result<Self> {
+        let mut words = step.splitn(2, ' ');
+        let keyword = words.next().unwrap_or_default();
+        let rest = words.next().unwrap_or_default().trim();
+        if keyword.eq_ignore_ascii_case("sleep") {
+            let seconds = rest.parse::<f64>()?;
+            return Ok(PerformStep::Sleep(Duration::try_from_secs_f64(seconds)?));
+        }
+        if keyword.eq_ignore_ascii_case("wait") {
+            let mut words = rest.splitn(3, ' ');
+            let first = words.next().unwrap_or_default();
+            if first.eq_ignore_ascii_case("notice") {
+                let nickname = words
+                    .next()
+                    .filter(|nickname| !nickname.is_empty())
+                    .ok_or_else(|| anyhow!("wait notice needs a nickname"))?;
+                return Ok(PerformStep::Wait(Expectation::Notice {
+                    nickname: nickname.to_owned(),
+                    text: words.next().unwrap_or("*").trim().to_owned(),
+                }));
+            }
+            return Ok(PerformStep::Wait(Expectation::Numeric(first.parse()?)));
+        }
+        Ok(PerformStep::Send(step.parse()?))
+    }
+}
+
+/// The commands to run once connected, before joining any channels.
+pub(crate) struct PerformScript {
+    steps: Vec<PerformStep>,
+    channels: Vec<String>,
+    timeout: Duration,
+}
+
+impl PerformScript {
+    /// Reads the steps from `IRC_PERFORM`, one per line. Lines can contain `;`, as passwords often
+    /// do. Called at startup so an invalid step stops the bot there rather than on every connect.
+    pub(crate) fn from_env() -> Self {
+        let steps = env::var("IRC_PERFORM")
+            .unwrap_or("".to_owned())
+            .lines()
+            .map(str::trim)
+            .filter(|step| !step.is_empty())
+            .map(|step| {
+                PerformStep::parse(step).unwrap_or_else(|error| {
+                    panic!("IRC_PERFORM step `{}` is invalid: {}", step, error)
+                })
+            })
+            .collect();
+        let channels = env::var("IRC_CHANNELS")
+            .expect("IRC_CHANNELS must be set")
+            .split(',')
+            .map(str::trim)
+            .filter(|channel| !channel.is_empty())
+            .map(ToOwned::to_owned)
+            .collect();
+        let timeout = Duration::from_secs(
+            env::var("IRC_PERFORM_TIMEOUT")
+                .unwrap_or("30".to_owned())
+                .parse()
+                .expect("IRC_PERFORM_TIMEOUT must be a number"),
+        );
+        PerformScript {
+            steps,
+            channels,
+            timeout,
+        }
+    }
+
+    async fn run_steps(
+        &self,
+        sender: &Sender,
+        messages: &mut UnboundedReceiver<Message>,
+    ) -> Result<()> {
+        for step in &self.steps {
+            match step {
+                PerformStep::Send(message) => sender.send(message.clone())?,
+                PerformStep::Sleep(duration) => sleep(*duration).await,
+                PerformStep::Wait(expectation) => {
+                    let deadline = Instant::now() + self.timeout;
+                    loop {
+                        match timeout_at(deadline, messages.recv()).await {
+                            Ok(Some(message)) if expectation.matches(&message) => break,
+                            Ok(Some(_)) => {}
+                            Ok(None) => return Err(anyhow!("disconnected")),
+                            Err(_) => {
+                                return Err(anyhow!("timed out waiting for {:?}", expectation))
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the steps in order with the messages the server sends in the meantime, then joins the
+    /// channels. If a step fails the connection is dropped, so it's retried from the start on
+    /// reconnect rather than leaving the bot in channels it may not be identified for.
+    pub(crate) async fn run(
+        self: Arc<Self>,
+        sender: Sender,
+        mut messages: UnboundedReceiver<Message>,
+    ) {
+        if let Err(error) = self.run_steps(&sender, &mut messages).await {
+            error!("IRC perform failed: {}", error);
+            if let Err(error) = sender.send_quit("Perform failed") {
+                error!("Error disconnecting from IRC: {}", error);
+            }
+            return;
+        }
+        if !self.steps.is_empty() {
+            info!("IRC perform finished, joining channels");
+        }
+        for channel in &self.channels {
+            if let Err(error) = sender.send_join(channel) {
+                error!("Error joining {}: {}", channel, error);
+            }
+        }
+    }
+}
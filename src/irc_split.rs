@@ -0,0 +1,161 @@
+use crate::formatting::{self, Style};
+
+const CTCP: char = '\x01';
+
+/// Renders styled characters as an IRC line, opening the formatting they need and closing it at
+/// the end.
+fn render(chars: &[(char, Style)]) -> String {
+    let mut spans = Vec::new();
+    for (character, style) in chars {
+        formatting::push_span(&mut spans, character.encode_utf8(&mut [0; 4]), *style);
+    }
+    formatting::render_irc(&spans)
+}
+
+/// Splits a line of formatted text into chunks of at most `max_bytes` bytes. Splits happen at
+/// spaces where possible and never inside a character, and every chunk is rendered on its own, so
+/// active formatting is restored at the start of each continuation line.
+fn split_formatted(line: &str, max_bytes: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current: Vec<(char, Style)> = Vec::new();
+    for span in formatting::parse_irc(line) {
+        for character in span.text.chars() {
+            current.push((character, span.style));
+            while current.len() > 1 && render(&current).len() > max_bytes {
+                // Break at the last space, or right before the character that didn't fit.
+                let last = current.len() - 1;
+                let (end, start) = match current.iter().rposition(|(c, _)| *c == ' ') {
+                    Some(space) if space > 0 => (space, space + 1),
+                    _ => (last, last),
+                };
+                let mut rest = current.split_off(start);
+                current.truncate(end);
+                lines.push(render(&current));
+                // Don't start a continuation line with the space we split at.
+                if rest.first().is_some_and(|(c, _)| *c == ' ') {
+                    rest.remove(0);
+                }
+                current = rest;
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push(render(&current));
+    }
+    lines
+}
+
+/// Splits a single line into chunks of at most `max_bytes` bytes. CTCP messages like ACTION are
+/// split inside their wrapper, so every chunk is still an ACTION.
+fn split_line(line: &str, max_bytes: usize) -> Vec<String> {
+    let Some(body) = line.strip_prefix(CTCP) else {
+        return split_formatted(line, max_bytes);
+    };
+    let body = body.strip_suffix(CTCP).unwrap_or(body);
+    match body.split_once(' ') {
+        Some((command, text)) => {
+            let wrapper_length = command.len() + 3;
+            split_formatted(text, max_bytes.saturating_sub(wrapper_length))
+                .into_iter()
+                .map(|chunk| format!("{CTCP}{command} {chunk}{CTCP}"))
+                .collect()
+        }
+        None => vec![line.to_owned()],
+    }
+}
+
+/// Splits a message into IRC lines of at most `max_bytes` bytes each, dropping empty lines. The
+/// formatting is re-rendered from [`formatting::parse_irc`], which drops hex colours.
+pub(crate) fn split_message(message: &str, max_bytes: usize) -> Vec<String> {
+    message
+        .lines()
+        .flat_map(|line| split_line(line, max_bytes))
+        .filter(|line| !formatting::strip_irc(line).trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_message;
+    use crate::formatting::{parse_irc, strip_irc};
+
+    #[test]
+    fn splits_at_spaces() {
+        assert_eq!(
+            split_message("one two three four", 9),
+            ["one two", "three", "four"]
+        );
+    }
+
+    #[test]
+    fn splits_words_longer_than_a_line() {
+        assert_eq!(split_message("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn keeps_multibyte_characters_whole() {
+        // Each é is two bytes, so a five byte limit fits two of them.
+        assert_eq!(split_message("ééééé", 5), ["éé", "éé", "é"]);
+        assert_eq!(split_message("a🎵b", 4), ["a", "🎵", "b"]);
+        for chunk in split_message("ab ééé 🎵🎵 c", 5) {
+            assert!(chunk.len() <= 5, "chunk too long: {chunk:?}");
+        }
+    }
+
+    #[test]
+    fn restores_colours_on_continuation_lines() {
+        assert_eq!(
+            split_message("\x0304,01red words here", 14),
+            [
+                "\x0304,01red\x03",
+                "\x0304,01words\x03",
+                "\x0304,01here\x03"
+            ]
+        );
+    }
+
+    #[test]
+    fn never_splits_colour_codes() {
+        let message = "\x0304a\x0312b\x0303c\x0307d";
+        // The shortest chunk is a coloured letter and the code ending the colour.
+        for max_bytes in 5..message.len() {
+            let chunks = split_message(message, max_bytes);
+            for chunk in &chunks {
+                assert!(chunk.len() <= max_bytes, "chunk too long: {chunk:?}");
+                assert!(
+                    parse_irc(chunk)
+                        .iter()
+                        .all(|span| span.style.foreground.is_some()),
+                    "colour code split: {chunk:?}"
+                );
+            }
+            let text = chunks
+                .iter()
+                .map(|chunk| strip_irc(chunk))
+                .collect::<String>();
+            assert_eq!(text, "abcd", "max_bytes: {max_bytes}");
+        }
+    }
+
+    #[test]
+    fn reset_ends_formatting_for_later_lines() {
+        assert_eq!(
+            split_message("\x02bold\x0f plain text here", 10),
+            ["\x02bold\x02", "plain text", "here"]
+        );
+        assert_eq!(split_message("\x0fplain", 10), ["plain"]);
+    }
+
+    #[test]
+    fn splits_actions_inside_the_wrapper() {
+        assert_eq!(
+            split_message("\x01ACTION waves at everyone\x01", 20),
+            ["\x01ACTION waves at\x01", "\x01ACTION everyone\x01"]
+        );
+    }
+
+    #[test]
+    fn drops_empty_lines() {
+        assert_eq!(split_message("a\n\n \n\x02\x02\nb", 10), ["a", "b"]);
+    }
+}
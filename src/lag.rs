@@ -0,0 +1,186 @@
+use crate::context::Context;
+use crate::irc_nick;
+use anyhow::{anyhow, Result};
+use irc::client::prelude::*;
+use log::warn;
+use std::env;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+
+/// Round-trip times of our own IRC PINGs. A PING left unanswered for longer than the threshold
+/// means the connection is dead even if the socket isn't closed yet. This is quicker than the
+/// `irc` crate's own keepalive, which is left at its defaults.
+pub(crate) struct IrcLag {
+    pub(crate) interval: Duration,
+    threshold: Duration,
+    outstanding: Option<(String, Instant)>,
+    last: Option<Duration>,
+}
+
+impl IrcLag {
+    pub(crate) fn new(interval: Duration, threshold: Duration) -> Self {
+        IrcLag {
+            interval,
+            threshold,
+            outstanding: None,
+            last: None,
+        }
+    }
+
+    pub(crate) fn from_env() -> Self {
+        // A zero interval would make the check's timer panic.
+        let seconds = |name: &str, default: &str| {
+            Duration::from_secs(
+                env::var(name)
+                    .unwrap_or(default.to_owned())
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds > 0)
+                    .unwrap_or_else(|| panic!("{} must be a number above 0", name)),
+            )
+        };
+        IrcLag::new(
+            seconds("IRC_LAG_CHECK_INTERVAL", "30"),
+            seconds("IRC_LAG_THRESHOLD", "60"),
+        )
+    }
+
+    /// Forgets the measurements of a previous connection.
+    pub(crate) fn reset(&mut self) {
+        self.outstanding = None;
+        self.last = None;
+    }
+
+    /// The last measured lag, or how long the current PING has been waiting if that's longer.
+    pub(crate) fn current(&self) -> Option<Duration> {
+        let waiting = self.outstanding.as_ref().map(|(_, sent)| sent.elapsed());
+        match (self.last, waiting) {
+            (Some(last), Some(waiting)) => Some(last.max(waiting)),
+            (last, waiting) => last.or(waiting),
+        }
+    }
+
+    /// Records the round trip if `token` is the one our last PING carried, returning it.
+    fn pong(&mut self, token: &str) -> Option<Duration> {
+        let (_, sent) = self
+            .outstanding
+            .take_if(|(sent_token, _)| sent_token == token)?;
+        let round_trip = sent.elapsed();
+        self.last = Some(round_trip);
+        Some(round_trip)
+    }
+}
+
+/// Sends a PING if none is waiting for an answer, or fails if the one that is has taken too long.
+pub(crate) fn check(client: &Client, context: &Context) -> Result<()> {
+    // Servers don't answer before registration completes.
+    if irc_nick::current_nickname(context).is_empty() {
+        return Ok(());
+    }
+    let mut lag = context
+        .irc_lag
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some((_, sent)) = &lag.outstanding {
+        let waiting = sent.elapsed();
+        if waiting > lag.threshold {
+            return Err(anyhow!(
+                "IRC server hasn't answered a PING in {} seconds",
+                waiting.as_secs()
+            ));
+        }
+        return Ok(());
+    }
+    let token = format!("lag-{}", uuid::Uuid::new_v4().simple());
+    client.send(Command::PING(token.clone(), None))?;
+    lag.outstanding = Some((token, Instant::now()));
+    Ok(())
+}
+
+/// Measures the round trip if `token` is the one our last PING carried.
+pub(crate) fn handle_pong(context: &Context, token: &str) {
+    let mut lag = context
+        .irc_lag
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let Some(round_trip) = lag.pong(token) else {
+        return;
+    };
+    if round_trip > lag.threshold / 2 {
+        warn!("IRC lag is {} ms", round_trip.as_millis());
+    }
+}
+
+pub(crate) fn irc_lag(context: &Context) -> Option<Duration> {
+    context
+        .irc_lag
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .current()
+}
+
+/// The gateway heartbeat latency of the Discord shard, once it's been measured.
+pub(crate) async fn discord_latency(context: &Context) -> Option<Duration> {
+    context
+        .discord_shard_manager
+        .runners
+        .lock()
+        .await
+        .values()
+        .find_map(|runner| runner.latency)
+}
+
+pub(crate) fn format_lag(lag: Option<Duration>) -> String {
+    match lag {
+        Some(lag) => format!("{} ms", lag.as_millis()),
+        None => "unknown".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IrcLag;
+    use std::time::{Duration, Instant};
+
+    fn lag() -> IrcLag {
+        IrcLag::new(Duration::from_secs(30), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn unknown_until_a_ping_is_sent() {
+        assert_eq!(lag().current(), None);
+    }
+
+    #[test]
+    fn measures_the_matching_pong() {
+        let mut lag = lag();
+        let sent = Instant::now() - Duration::from_secs(2);
+        lag.outstanding = Some(("lag-1".to_owned(), sent));
+        assert_eq!(lag.pong("lag-2"), None);
+        assert!(lag.outstanding.is_some());
+        let round_trip = lag.pong("lag-1").unwrap();
+        assert!(round_trip >= Duration::from_secs(2));
+        assert!(lag.outstanding.is_none());
+        assert_eq!(lag.current(), Some(round_trip));
+    }
+
+    #[test]
+    fn waiting_ping_counts_once_it_exceeds_the_last_lag() {
+        let mut lag = lag();
+        lag.last = Some(Duration::from_secs(1));
+        lag.outstanding = Some(("lag-1".to_owned(), Instant::now()));
+        assert_eq!(lag.current(), Some(Duration::from_secs(1)));
+        lag.outstanding = Some(("lag-1".to_owned(), Instant::now() - Duration::from_secs(5)));
+        assert!(lag.current().unwrap() >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reset_forgets_the_previous_connection() {
+        let mut lag = lag();
+        lag.last = Some(Duration::from_secs(1));
+        lag.outstanding = Some(("lag-1".to_owned(), Instant::now()));
+        lag.reset();
+        assert_eq!(lag.current(), None);
+        assert_eq!(lag.pong("lag-1"), None);
+    }
+}
@@ -1,18 +1,57 @@
+mod accounts;
+mod ansi_relay;
 mod api;
+mod avatars;
 mod commands;
 mod context;
 mod discord;
+mod events;
+mod formatting;
+mod graph;
 mod irc;
+mod irc_caps;
+mod irc_link;
+mod irc_nick;
+mod irc_perform;
+mod irc_split;
+mod lag;
+mod mentions;
+mod metrics;
+mod paste;
+mod presence;
+mod scheduler;
 mod shazam;
+mod shutdown;
+mod storage;
+mod supervisor;
+mod timezones;
+mod topic;
 
+use crate::accounts::UnlinkedRateLimit;
+use crate::ansi_relay::AnsiRelay;
+use crate::avatars::AvatarIndex;
 use crate::context::{Context, NpState};
 use crate::discord::CommandContext;
+use crate::graph::ListenerGraphs;
 use crate::irc::IrcClientExt;
+use crate::irc_caps::SaslMechanism;
+use crate::irc_link::IrcLink;
+use crate::irc_nick::NickRegain;
+use crate::irc_perform::PerformScript;
+use crate::lag::IrcLag;
+use crate::metrics::Metrics;
+use crate::presence::PresenceRelay;
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::topic::TopicState;
 use discord::get_serenity_client;
 use dotenvy::dotenv;
-use serenity::all::ChannelId;
+use log::error;
+use serenity::all::{ChannelId, RoleId};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::sync::{atomic::AtomicBool, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() {
@@ -21,6 +60,7 @@ async fn main() {
         .expect("Failed to install default rustls provider");
     dotenv().ok();
     env_logger::init();
+    storage::check_writable();
 
     let mut discord_client = get_serenity_client().await;
     let irc_client = irc::get_irc_client()
@@ -28,6 +68,7 @@ async fn main() {
         .expect("Failed to connect to IRC server");
     let discord_http = discord_client.http.clone();
     let discord_cache = discord_client.cache.clone();
+    let discord_shard_manager = discord_client.shard_manager.clone();
     let irc_sender = irc_client.sender();
     let discord_channel: ChannelId = env::var("DISCORD_CHANNEL_ID")
         .expect("DISCORD_CHANNEL_ID must be set")
@@ -44,25 +85,105 @@ async fn main() {
     let shazam_irc_channel =
         env::var("SHAZAM_IRC_CHANNEL").expect("SHAZAM_IRC_CHANNEL must be set");
     let shazam_emoji = env::var("SHAZAM_EMOJI").ok().filter(|s| !s.is_empty());
+    let graph_discord_channel: Option<ChannelId> = env::var("LISTENER_GRAPH_DISCORD_CHANNEL_ID")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse()
+                .expect("LISTENER_GRAPH_DISCORD_CHANNEL_ID must be a number")
+        });
+    let discord_live_role: Option<RoleId> = env::var("DISCORD_LIVE_ROLE_ID")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse().expect("DISCORD_LIVE_ROLE_ID must be a number"));
+    let stream_url = env::var("STREAM_URL").unwrap_or("https://dnbradio.com/player".to_owned());
+    let scheduled_events = env::var("DISCORD_SYNC_EVENTS")
+        .unwrap_or("false".to_owned())
+        .parse::<bool>()
+        .expect("DISCORD_SYNC_EVENTS must be true or false")
+        .then(|| Arc::new(tokio::sync::Mutex::new(storage::load(events::STORAGE_NAME))));
+    let discord_topic_sync = env::var("DISCORD_TOPIC_SYNC")
+        .unwrap_or("false".to_owned())
+        .parse::<bool>()
+        .expect("DISCORD_TOPIC_SYNC must be true or false");
+    let irc_admins = env::var("IRC_ADMINS")
+        .unwrap_or("".to_owned())
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect();
+    // Off by default, as it sends the nick of everyone without an avatar to the identicon service.
+    let identicon_url = env::var("IRC_IDENTICON_URL").ok().filter(|s| !s.is_empty());
+    let account_link_url =
+        env::var("ACCOUNT_LINK_URL").unwrap_or("https://dnbradio.com/account/link".to_owned());
+    let unlinked_rate_limit_interval = Duration::from_secs(
+        env::var("UNLINKED_RATE_LIMIT")
+            .unwrap_or("300".to_owned())
+            .parse()
+            .expect("UNLINKED_RATE_LIMIT must be a number"),
+    );
+    let paste_url = env::var("PASTE_URL").ok().filter(|s| !s.is_empty());
+    let paste_threshold = env::var("PASTE_THRESHOLD_LINES")
+        .unwrap_or("3".to_owned())
+        .parse()
+        .expect("PASTE_THRESHOLD_LINES must be a number");
 
     let context = Context {
         discord_http,
         discord_cache,
+        discord_shard_manager,
         discord_channel,
         discord_webhook_url,
+        discord_relayed: Arc::new(Mutex::new(VecDeque::new())),
+        unknown_mentions: Arc::new(Mutex::new(HashSet::new())),
         irc_sender: Arc::new(RwLock::new(irc_sender)),
         irc_channel,
+        irc_names: Arc::new(RwLock::new(HashSet::new())),
+        irc_presence: Arc::new(Mutex::new(PresenceRelay::from_env())),
+        ansi_relay: Arc::new(Mutex::new(AnsiRelay::from_env())),
+        irc_admins,
+        irc_sasl: SaslMechanism::from_env(),
+        irc_caps: Arc::new(RwLock::new(HashSet::new())),
+        irc_accounts: Arc::new(RwLock::new(HashMap::new())),
+        irc_message_ids: Arc::new(Mutex::new(VecDeque::new())),
+        irc_nickname: Arc::new(RwLock::new(String::new())),
+        irc_link: Arc::new(Mutex::new(IrcLink::from_env())),
+        irc_lag: Arc::new(Mutex::new(IrcLag::from_env())),
+        metrics: Arc::new(Metrics::default()),
+        restart_policy: Arc::new(RestartPolicy::from_env()),
+        shutting_down: Arc::new(AtomicBool::new(false)),
+        irc_nick_regain: NickRegain::from_env(),
+        irc_perform: Arc::new(PerformScript::from_env()),
         command_prefix,
-        last_track: Arc::new(RwLock::new(None)),
+        last_track: Arc::new(RwLock::new(storage::load(shazam::STORAGE_NAME))),
         shazam_discord_channel,
         shazam_irc_channel,
         shazam_emoji,
         shazam_active: Arc::new(AtomicBool::new(false)),
-        np_state: Arc::new(Mutex::new(NpState {
-            message_id: None,
-            lines: std::collections::VecDeque::new(),
-        })),
-        np_someone_talked: Arc::new(AtomicBool::new(false)),
+        np_state: Arc::new(Mutex::new(storage::load::<NpState>(
+            context::NP_STATE_STORAGE_NAME,
+        ))),
+        np_someone_talked: Arc::new(AtomicBool::new(storage::load(
+            context::NP_SOMEONE_TALKED_STORAGE_NAME,
+        ))),
+        listener_graphs: Arc::new(Mutex::new(ListenerGraphs::default())),
+        graph_discord_channel,
+        discord_live_role,
+        stream_url,
+        scheduled_events,
+        timezones: Arc::new(RwLock::new(storage::load(timezones::STORAGE_NAME))),
+        topic_state: Arc::new(Mutex::new(TopicState::default())),
+        discord_topic_sync,
+        paste_url,
+        paste_threshold,
+        avatars: Arc::new(RwLock::new(AvatarIndex::default())),
+        nick_links: Arc::new(RwLock::new(storage::load(avatars::STORAGE_NAME))),
+        identicon_url,
+        account_links: Arc::new(RwLock::new(storage::load(accounts::STORAGE_NAME))),
+        account_link_url,
+        unlinked_rate_limit: Arc::new(Mutex::new(UnlinkedRateLimit::default())),
+        unlinked_rate_limit_interval,
     };
 
     discord_client
@@ -70,18 +191,63 @@ async fn main() {
         .write()
         .await
         .insert::<CommandContext>(context.clone());
+    context.restore_np_state().await;
 
-    let discord_handle = tokio::spawn(async move { discord_client.start().await });
-    let irc_context = context.clone();
-    let irc_handle = tokio::spawn(async move { irc_client.start(irc_context).await });
-    let shazam_context = context.clone();
-    let shazam_handle = tokio::spawn(async move { shazam::start(shazam_context).await });
-    let now_playing_handle = tokio::spawn(async move { api::now_playing_loop(context).await });
+    // Serenity reconnects the gateway itself, so if the client stops it's for good and Docker
+    // should restart the bot.
+    let discord_context = context.clone();
+    let discord_handle = tokio::spawn(async move {
+        if let Err(error) = discord_client.start().await {
+            error!("Discord client stopped: {:?}", error);
+        }
+        if !discord_context.shutting_down.load(Ordering::Acquire) {
+            std::process::exit(1);
+        }
+    });
+    // The first run of the IRC task uses the connection made above, restarts make a new one.
+    let irc_client = Arc::new(Mutex::new(Some(irc_client)));
+    let irc_handle = tokio::spawn(supervise(context.clone(), "irc", move |context| {
+        let client = irc_client
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        async move {
+            match client {
+                Some(client) => client.start(context).await,
+                None => irc::reconnect(context).await,
+            }
+        }
+    }));
+    let shazam_handle = tokio::spawn(supervise(context.clone(), "shazam", shazam::start));
+    let presence_handle = tokio::spawn(supervise(context.clone(), "presence", presence::start));
+    let ansi_handle = tokio::spawn(supervise(context.clone(), "ansi_relay", ansi_relay::start));
+    let avatars_handle = tokio::spawn(supervise(context.clone(), "avatars", avatars::start));
+    let nick_handle = tokio::spawn(supervise(context.clone(), "irc_nick", irc_nick::start));
+    let metrics_handle = tokio::spawn(supervise(context.clone(), "metrics", metrics::start));
+    let topic_handle = tokio::spawn(supervise(context.clone(), "topic", topic::start));
+    let scheduler_handle = tokio::spawn(supervise(context.clone(), "scheduler", scheduler::start));
+    let now_playing_handle = tokio::spawn(supervise(
+        context.clone(),
+        "now_playing",
+        api::now_playing_loop,
+    ));
 
-    _ = tokio::join!(
-        discord_handle,
-        irc_handle,
-        shazam_handle,
-        now_playing_handle
-    );
+    tokio::select! {
+        _ = async {
+            tokio::join!(
+                discord_handle,
+                irc_handle,
+                shazam_handle,
+                scheduler_handle,
+                presence_handle,
+                ansi_handle,
+                avatars_handle,
+                nick_handle,
+                metrics_handle,
+                topic_handle,
+                now_playing_handle
+            )
+        } => {}
+        _ = shutdown::wait_for_signal() => shutdown::shutdown(&context).await,
+    }
 }
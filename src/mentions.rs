@@ -0,0 +1,353 @@
+use crate::context::Context;
+use chrono::{DateTime, Utc};
+use serenity::all::{ChannelId, Guild, GuildId, RoleId, User, UserId};
+use std::collections::HashSet;
+use std::sync::PoisonError;
+
+/// How many mentions in a single message may be looked up with the API.
+const MAX_LOOKUPS_PER_MESSAGE: usize = 5;
+/// How many IDs the API couldn't find are remembered.
+const MAX_UNKNOWN_IDS: usize = 1000;
+
+/// IDs the API couldn't find, so made up mentions don't get looked up again.
+pub(crate) type UnknownMentions = HashSet<u64>;
+
+/// Limits the API lookups made while resolving a single message.
+struct Lookups<'a> {
+    context: &'a Context,
+    remaining: usize,
+}
+
+impl Lookups<'_> {
+    /// Whether `id` may be looked up with the API, counting it towards the limit if so.
+    fn start(&mut self, id: u64) -> bool {
+        if self.remaining == 0
+            || self
+                .context
+                .unknown_mentions
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .contains(&id)
+        {
+            return false;
+        }
+        self.remaining -= 1;
+        true
+    }
+
+    fn not_found(&self, id: u64) {
+        let mut unknown = self
+            .context
+            .unknown_mentions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if unknown.len() >= MAX_UNKNOWN_IDS {
+            unknown.clear();
+        }
+        unknown.insert(id);
+    }
+}
+
+enum Mention {
+    User(UserId),
+    Channel(ChannelId),
+    Role(RoleId),
+    Timestamp(i64, Option<char>),
+}
+
+fn parse_id(text: &str) -> Option<u64> {
+    if text.is_empty() || !text.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok().filter(|id| *id != 0)
+}
+
+/// Parses the text between the angle brackets of a Discord mention or timestamp.
+fn parse_mention(inner: &str) -> Option<Mention> {
+    if let Some(id) = inner.strip_prefix("@&") {
+        return parse_id(id).map(|id| Mention::Role(RoleId::new(id)));
+    }
+    if let Some(id) = inner.strip_prefix('@') {
+        let id = id.strip_prefix('!').unwrap_or(id);
+        return parse_id(id).map(|id| Mention::User(UserId::new(id)));
+    }
+    if let Some(id) = inner.strip_prefix('#') {
+        return parse_id(id).map(|id| Mention::Channel(ChannelId::new(id)));
+    }
+    let timestamp = inner.strip_prefix("t:")?;
+    let (timestamp, style) = match timestamp.split_once(':') {
+        Some((timestamp, style)) => {
+            let mut chars = style.chars();
+            match (chars.next(), chars.next()) {
+                (Some(style), None) if "tTdDfFR".contains(style) => (timestamp, Some(style)),
+                _ => return None,
+            }
+        }
+        None => (timestamp, None),
+    };
+    Some(Mention::Timestamp(timestamp.parse().ok()?, style))
+}
+
+fn format_relative(time: DateTime<Utc>) -> String {
+    let seconds = (time - Utc::now()).num_seconds();
+    let distance = seconds.unsigned_abs();
+    let (amount, unit) = match distance {
+        0..60 => (distance, "second"),
+        60..3600 => (distance / 60, "minute"),
+        3600..86400 => (distance / 3600, "hour"),
+        86400..2592000 => (distance / 86400, "day"),
+        2592000..31536000 => (distance / 2592000, "month"),
+        _ => (distance / 31536000, "year"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    if seconds >= 0 {
+        format!("in {amount} {unit}{plural}")
+    } else {
+        format!("{amount} {unit}{plural} ago")
+    }
+}
+
+/// Formats a `<t:…>` timestamp in UTC, following the style Discord would display it in.
+fn format_timestamp(timestamp: i64, style: Option<char>) -> Option<String> {
+    let time = DateTime::<Utc>::from_timestamp(timestamp, 0)?;
+    let format = match style {
+        Some('t') => "%H:%M UTC",
+        Some('T') => "%H:%M:%S UTC",
+        Some('d') => "%Y-%m-%d",
+        Some('D') => "%-d %B %Y",
+        Some('F') => "%A, %-d %B %Y %H:%M UTC",
+        Some('R') => {
+            return Some(format!(
+                "{} ({})",
+                format_relative(time),
+                time.format("%Y-%m-%d %H:%M UTC")
+            ))
+        }
+        _ => "%-d %B %Y %H:%M UTC",
+    };
+    Some(time.format(format).to_string())
+}
+
+fn with_cached_guild<T>(
+    context: &Context,
+    guild_id: Option<GuildId>,
+    f: impl FnOnce(&Guild) -> Option<T>,
+) -> Option<T> {
+    let guild = context.discord_cache.guild(guild_id?)?;
+    f(&guild)
+}
+
+async fn user_name(
+    context: &Context,
+    lookups: &mut Lookups<'_>,
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+    mentions: &[User],
+) -> String {
+    let cached = with_cached_guild(context, guild_id, |guild| {
+        guild
+            .members
+            .get(&user_id)
+            .map(|member| member.display_name().to_owned())
+    });
+    if let Some(name) = cached {
+        return name;
+    }
+    if let Some(guild_id) = guild_id.filter(|_| lookups.start(user_id.get())) {
+        match guild_id.member(&context.discord_http, user_id).await {
+            Ok(member) => return member.display_name().to_owned(),
+            Err(_) => lookups.not_found(user_id.get()),
+        }
+    }
+    match mentions.iter().find(|user| user.id == user_id) {
+        Some(user) => user
+            .global_name
+            .clone()
+            .unwrap_or_else(|| user.name.clone()),
+        None => "unknown-user".to_owned(),
+    }
+}
+
+async fn channel_name(
+    context: &Context,
+    lookups: &mut Lookups<'_>,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> String {
+    let cached = with_cached_guild(context, guild_id, |guild| {
+        guild
+            .channels
+            .get(&channel_id)
+            .or_else(|| guild.threads.iter().find(|thread| thread.id == channel_id))
+            .map(|channel| channel.name.clone())
+    });
+    if let Some(name) = cached {
+        return name;
+    }
+    if !lookups.start(channel_id.get()) {
+        return "unknown-channel".to_owned();
+    }
+    match channel_id.to_channel(&context.discord_http).await {
+        Ok(channel) => channel
+            .guild()
+            .map_or("unknown-channel".to_owned(), |channel| channel.name),
+        Err(_) => {
+            lookups.not_found(channel_id.get());
+            "unknown-channel".to_owned()
+        }
+    }
+}
+
+async fn role_name(
+    context: &Context,
+    lookups: &mut Lookups<'_>,
+    guild_id: Option<GuildId>,
+    role_id: RoleId,
+) -> String {
+    let cached = with_cached_guild(context, guild_id, |guild| {
+        guild.roles.get(&role_id).map(|role| role.name.clone())
+    });
+    if let Some(name) = cached {
+        return name;
+    }
+    if let Some(guild_id) = guild_id.filter(|_| lookups.start(role_id.get())) {
+        match guild_id.roles(&context.discord_http).await {
+            Ok(roles) => match roles.get(&role_id) {
+                Some(role) => return role.name.clone(),
+                None => lookups.not_found(role_id.get()),
+            },
+            Err(_) => lookups.not_found(role_id.get()),
+        }
+    }
+    "unknown-role".to_owned()
+}
+
+async fn describe_mention(
+    context: &Context,
+    lookups: &mut Lookups<'_>,
+    guild_id: Option<GuildId>,
+    mention: Mention,
+    mentions: &[User],
+) -> Option<String> {
+    let name = match mention {
+        Mention::User(user_id) => {
+            let name = user_name(context, lookups, guild_id, user_id, mentions).await;
+            format!("@{}", name)
+        }
+        Mention::Channel(channel_id) => {
+            let name = channel_name(context, lookups, guild_id, channel_id).await;
+            format!("#{}", name)
+        }
+        Mention::Role(role_id) => {
+            // The @everyone role is already named with an @.
+            let name = role_name(context, lookups, guild_id, role_id).await;
+            format!("@{}", name.trim_start_matches('@'))
+        }
+        Mention::Timestamp(timestamp, style) => format_timestamp(timestamp, style)?,
+    };
+    Some(Context::escape_discord_markdown(&name))
+}
+
+/// Splits a message into text and the code spans and blocks in it, which are `true`.
+fn split_code(message: &str) -> Vec<(bool, &str)> {
+    let mut parts = Vec::new();
+    let mut text_start = 0;
+    let mut index = 0;
+    let backticks = |index: usize| message[index..].bytes().take_while(|b| *b == b'`').count();
+    while index < message.len() {
+        match message.as_bytes()[index] {
+            b'\\' => index += 2,
+            b'`' => {
+                let run = backticks(index);
+                // Code blocks end at the next ```, code spans at a run of exactly as many backticks.
+                let length = if run >= 3 { 3 } else { run };
+                let mut end = index + run;
+                let close = loop {
+                    match message[end..].find('`') {
+                        Some(offset) => {
+                            let close = end + offset;
+                            let close_run = backticks(close);
+                            if close_run == length || (length == 3 && close_run >= 3) {
+                                break Some(close + length);
+                            }
+                            end = close + close_run;
+                        }
+                        None => break None,
+                    }
+                };
+                match close {
+                    Some(close) => {
+                        parts.push((false, &message[text_start..index]));
+                        parts.push((true, &message[index..close]));
+                        text_start = close;
+                        index = close;
+                    }
+                    None => index += run,
+                }
+            }
+            _ => index += 1,
+        }
+    }
+    parts.push((false, &message[text_start..]));
+    parts.retain(|(_, part)| !part.is_empty());
+    parts
+}
+
+/// Replaces user, channel and role mentions and `<t:…>` timestamps in a Discord message with
+/// readable text, leaving code alone. Names come from the cache where possible, then from the API,
+/// and finally from the users mentioned in the message itself.
+pub(crate) async fn resolve_mentions(
+    context: &Context,
+    guild_id: Option<GuildId>,
+    message: &str,
+    mentions: &[User],
+) -> String {
+    let mut lookups = Lookups {
+        context,
+        remaining: MAX_LOOKUPS_PER_MESSAGE,
+    };
+    let mut output = String::new();
+    for (is_code, part) in split_code(message) {
+        if is_code {
+            output.push_str(part);
+        } else {
+            resolve_text(context, &mut lookups, guild_id, part, mentions, &mut output).await;
+        }
+    }
+    output
+}
+
+async fn resolve_text(
+    context: &Context,
+    lookups: &mut Lookups<'_>,
+    guild_id: Option<GuildId>,
+    message: &str,
+    mentions: &[User],
+    output: &mut String,
+) {
+    let mut rest = message;
+    while let Some(start) = rest.find('<') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let mention = rest
+            .find('>')
+            .and_then(|end| parse_mention(&rest[1..end]).map(|mention| (mention, end)));
+        let described = match mention {
+            Some((mention, end)) => describe_mention(context, lookups, guild_id, mention, mentions)
+                .await
+                .map(|described| (described, end)),
+            None => None,
+        };
+        match described {
+            Some((described, end)) => {
+                output.push_str(&described);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                output.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+}
@@ -0,0 +1,309 @@
+use crate::context::Context;
+use crate::lag;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use serenity::gateway::ConnectionStage;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Watched tasks are stale after missing three heartbeats, but never sooner than this.
+const MIN_STALE_AFTER: Duration = Duration::from_secs(60);
+/// How long a client gets to send its request line, and how long that line may be.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_LINE: u64 = 1024;
+
+/// Counters for `/metrics`, and when each background task last got something done for `/healthz`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) relayed_irc_to_discord: AtomicU64,
+    pub(crate) relayed_discord_to_irc: AtomicU64,
+    pub(crate) commands: AtomicU64,
+    pub(crate) api_errors: AtomicU64,
+    pub(crate) shazam_attempts: AtomicU64,
+    pub(crate) shazam_matches: AtomicU64,
+    pub(crate) listeners: AtomicU64,
+    activity: Mutex<BTreeMap<&'static str, DateTime<Utc>>>,
+    stale_after: Mutex<BTreeMap<&'static str, Duration>>,
+}
+
+impl Metrics {
+    /// Records that the task spawned as `task` in `main.rs` is alive and working.
+    pub(crate) fn heartbeat(&self, task: &'static str) {
+        self.activity
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(task, Utc::now());
+    }
+
+    /// Makes `/healthz` unhealthy once `task`, which heartbeats every `interval`, stops doing so.
+    /// Tasks that only heartbeat when something happens, like Discord messages, aren't watched.
+    pub(crate) fn watch(&self, task: &'static str, interval: Duration) {
+        let stale_after = (interval * 3).max(MIN_STALE_AFTER);
+        self.stale_after
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(task, stale_after);
+    }
+}
+
+#[derive(Serialize)]
+struct TaskHealth {
+    last_activity: DateTime<Utc>,
+    seconds_ago: i64,
+    stale: bool,
+}
+
+#[derive(Serialize)]
+struct Health {
+    healthy: bool,
+    irc_connected: bool,
+    irc_lag_ms: Option<u128>,
+    discord_connected: bool,
+    discord_latency_ms: Option<u128>,
+    tasks: BTreeMap<&'static str, TaskHealth>,
+}
+
+async fn discord_connected(context: &Context) -> bool {
+    context
+        .discord_shard_manager
+        .runners
+        .lock()
+        .await
+        .values()
+        .any(|runner| runner.stage == ConnectionStage::Connected)
+}
+
+/// Healthy means both chat links are up and no watched task is stale. The last activity of every
+/// task is included so a stuck loop can be spotted.
+async fn health(context: &Context) -> Health {
+    let irc_connected = context
+        .irc_link
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_connected();
+    let discord_connected = discord_connected(context).await;
+    let now = Utc::now();
+    let stale_after = context
+        .metrics
+        .stale_after
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    let tasks: BTreeMap<_, _> = context
+        .metrics
+        .activity
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|(task, last_activity)| {
+            let since = (now - *last_activity).to_std().unwrap_or_default();
+            (
+                *task,
+                TaskHealth {
+                    last_activity: *last_activity,
+                    seconds_ago: since.as_secs() as i64,
+                    stale: stale_after.get(task).is_some_and(|limit| since > *limit),
+                },
+            )
+        })
+        .collect();
+    Health {
+        healthy: irc_connected && discord_connected && !tasks.values().any(|task| task.stale),
+        irc_connected,
+        irc_lag_ms: lag::irc_lag(context).map(|lag| lag.as_millis()),
+        discord_connected,
+        discord_latency_ms: lag::discord_latency(context)
+            .await
+            .map(|latency| latency.as_millis()),
+        tasks,
+    }
+}
+
+fn write_metric(output: &mut String, name: &str, kind: &str, help: &str, values: &[(&str, f64)]) {
+    _ = writeln!(output, "# HELP {name} {help}");
+    _ = writeln!(output, "# TYPE {name} {kind}");
+    for (labels, value) in values {
+        if labels.is_empty() {
+            _ = writeln!(output, "{name} {value}");
+        } else {
+            _ = writeln!(output, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+/// Renders the metrics in the Prometheus text format.
+async fn render_metrics(context: &Context) -> String {
+    let metrics = &context.metrics;
+    let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+    let mut output = String::new();
+    write_metric(
+        &mut output,
+        "dnbradio_messages_relayed_total",
+        "counter",
+        "Messages relayed between IRC and Discord.",
+        &[
+            (
+                "direction=\"irc_to_discord\"",
+                count(&metrics.relayed_irc_to_discord),
+            ),
+            (
+                "direction=\"discord_to_irc\"",
+                count(&metrics.relayed_discord_to_irc),
+            ),
+        ],
+    );
+    write_metric(
+        &mut output,
+        "dnbradio_commands_total",
+        "counter",
+        "Commands run on either network.",
+        &[("", count(&metrics.commands))],
+    );
+    write_metric(
+        &mut output,
+        "dnbradio_api_errors_total",
+        "counter",
+        "Failed requests to the dnbradio.com and AzuraCast APIs.",
+        &[("", count(&metrics.api_errors))],
+    );
+    write_metric(
+        &mut output,
+        "dnbradio_shazam_attempts_total",
+        "counter",
+        "Attempts to recognise the track playing.",
+        &[("", count(&metrics.shazam_attempts))],
+    );
+    write_metric(
+        &mut output,
+        "dnbradio_shazam_matches_total",
+        "counter",
+        "Attempts that recognised the track playing.",
+        &[("", count(&metrics.shazam_matches))],
+    );
+    write_metric(
+        &mut output,
+        "dnbradio_listeners",
+        "gauge",
+        "Listeners tuned in at the last now playing check.",
+        &[("", count(&metrics.listeners))],
+    );
+    let irc_connected = context
+        .irc_link
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_connected();
+    write_metric(
+        &mut output,
+        "dnbradio_irc_connected",
+        "gauge",
+        "Whether the bot is connected to IRC and in its channel.",
+        &[("", if irc_connected { 1.0 } else { 0.0 })],
+    );
+    if let Some(lag) = lag::irc_lag(context) {
+        write_metric(
+            &mut output,
+            "dnbradio_irc_lag_seconds",
+            "gauge",
+            "Round trip time of the last IRC PING.",
+            &[("", lag.as_secs_f64())],
+        );
+    }
+    if let Some(latency) = lag::discord_latency(context).await {
+        write_metric(
+            &mut output,
+            "dnbradio_discord_latency_seconds",
+            "gauge",
+            "Discord gateway heartbeat latency.",
+            &[("", latency.as_secs_f64())],
+        );
+    }
+    let activity = metrics
+        .activity
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|(task, time)| (format!("task=\"{task}\""), time.timestamp() as f64))
+        .collect::<Vec<_>>();
+    write_metric(
+        &mut output,
+        "dnbradio_task_last_activity_timestamp_seconds",
+        "gauge",
+        "When each background task last got something done.",
+        &activity
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), *value))
+            .collect::<Vec<_>>(),
+    );
+    output
+}
+
+async fn handle_connection(context: &Context, mut stream: TcpStream) -> anyhow::Result<()> {
+    // Only the request line matters, so there's no need to read past it.
+    let mut request_line = String::new();
+    let mut reader = BufReader::new((&mut stream).take(MAX_REQUEST_LINE));
+    tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut request_line)).await??;
+    let mut request_line = request_line.trim_end().split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/healthz")) => {
+            let health = health(context).await;
+            let status = if health.healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, "application/json", serde_json::to_string(&health)?)
+        }
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render_metrics(context).await,
+        ),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Serves `/healthz` and `/metrics` on `METRICS_LISTEN`, unless it's empty.
+pub(crate) async fn start(context: Context) {
+    let address = env::var("METRICS_LISTEN").unwrap_or("0.0.0.0:8080".to_owned());
+    if address.is_empty() {
+        return;
+    }
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Error listening for metrics on {}: {:?}", address, error);
+            return;
+        }
+    };
+    info!("Serving health and metrics on {}", address);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                warn!("Error accepting metrics connection: {:?}", error);
+                continue;
+            }
+        };
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(error) = handle_connection(&context, stream).await {
+                warn!("Error serving metrics request: {:?}", error);
+            }
+        });
+    }
+}
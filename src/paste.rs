@@ -0,0 +1,73 @@
+use crate::context::Context;
+use crate::irc_split;
+use anyhow::{anyhow, Result};
+use log::error;
+use std::time::Duration;
+
+/// The most bytes of the first line to keep in front of the paste URL.
+const MAX_FIRST_LINE_BYTES: usize = 200;
+
+/// Uploads text to the paste backend, which is expected to accept the raw text as the POST body and
+/// respond with the URL of the paste.
+async fn upload(paste_url: &str, text: &str) -> Result<String> {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(paste_url)
+        .timeout(Duration::from_secs(10))
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(text.to_owned())
+        .send()
+        .await?
+        .error_for_status()?;
+    let url = response.text().await?.trim().to_owned();
+    if !url.starts_with("http") {
+        return Err(anyhow!("Paste backend returned an invalid URL: {}", url));
+    }
+    Ok(url)
+}
+
+fn truncate_to_bytes(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_owned();
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &text[..end])
+}
+
+/// Uploads a Discord message to the paste backend if it contains a code block or would take up too
+/// many IRC lines. Returns the text to relay to IRC instead: the first line and the paste URL.
+pub(crate) async fn paste_long_message(
+    context: &Context,
+    message: &str,
+    irc_message: &str,
+    nickname: &str,
+) -> Option<String> {
+    let paste_url = context.paste_url.as_ref()?;
+    let max_bytes = Context::irc_max_message_bytes(&context.irc_channel, &format!("<{nickname}> "));
+    let line_count = irc_split::split_message(irc_message, max_bytes).len();
+    if !message.contains("```") && line_count <= context.paste_threshold {
+        return None;
+    }
+
+    let url = match upload(paste_url, message).await {
+        Ok(url) => url,
+        Err(error) => {
+            error!("Error uploading message to paste backend: {:?}", error);
+            return None;
+        }
+    };
+    // Code fences are only visible before the message is translated for IRC.
+    let first_line = message
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| context.discord_markdown_to_irc(line))
+        .find(|line| !line.trim().is_empty())
+        .map(|line| truncate_to_bytes(line.trim(), MAX_FIRST_LINE_BYTES));
+    Some(match first_line {
+        Some(first_line) => format!("{first_line} (full message: {url})"),
+        None => format!("Pasted: {url}"),
+    })
+}
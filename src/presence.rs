@@ -0,0 +1,189 @@
+use crate::context::Context;
+use log::info;
+use std::env;
+use std::sync::PoisonError;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub(crate) enum PresenceEvent {
+    Join(String),
+    Part(String, Option<String>),
+    Quit(String, Option<String>),
+    Kick(String, String, Option<String>),
+    Nick(String, String),
+}
+
+impl PresenceEvent {
+    fn describe(&self, channel: &str) -> String {
+        let with_reason = |message: String, reason: &Option<String>| match reason {
+            Some(reason) if !reason.is_empty() => format!("{message} ({reason})"),
+            _ => message,
+        };
+        match self {
+            PresenceEvent::Join(nick) => format!("{nick} joined {channel}"),
+            PresenceEvent::Part(nick, reason) => {
+                with_reason(format!("{nick} left {channel}"), reason)
+            }
+            PresenceEvent::Quit(nick, reason) => with_reason(format!("{nick} quit IRC"), reason),
+            PresenceEvent::Kick(nick, by, reason) => {
+                with_reason(format!("{nick} was kicked from {channel} by {by}"), reason)
+            }
+            PresenceEvent::Nick(old, new) => format!("{old} is now known as {new}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PresenceRelayMode {
+    /// Don't relay IRC presence changes to Discord.
+    Off,
+    /// Send a line per event, falling back to a summary once the rate limit is hit.
+    Compact,
+    /// Only send a periodic summary of what happened.
+    Summary,
+}
+
+pub(crate) struct PresenceRelay {
+    pub(crate) mode: PresenceRelayMode,
+    rate_limit: usize,
+    interval: Duration,
+    sent_in_window: usize,
+    joined: usize,
+    left: usize,
+    renamed: usize,
+}
+
+impl PresenceRelay {
+    pub(crate) fn from_env() -> Self {
+        let mode = match env::var("IRC_PRESENCE_RELAY")
+            .unwrap_or("off".to_owned())
+            .as_str()
+        {
+            "off" => PresenceRelayMode::Off,
+            "compact" => PresenceRelayMode::Compact,
+            "summary" => PresenceRelayMode::Summary,
+            _ => panic!("IRC_PRESENCE_RELAY must be off, compact or summary"),
+        };
+        let rate_limit = env::var("IRC_PRESENCE_RATE_LIMIT")
+            .unwrap_or("5".to_owned())
+            .parse()
+            .expect("IRC_PRESENCE_RATE_LIMIT must be a number");
+        let interval = Duration::from_secs(
+            env::var("IRC_PRESENCE_SUMMARY_INTERVAL")
+                .unwrap_or("60".to_owned())
+                .parse()
+                .expect("IRC_PRESENCE_SUMMARY_INTERVAL must be a number"),
+        );
+        PresenceRelay {
+            mode,
+            rate_limit,
+            interval,
+            sent_in_window: 0,
+            joined: 0,
+            left: 0,
+            renamed: 0,
+        }
+    }
+
+    /// Returns the line to send right away, or counts the event towards the next summary.
+    fn record(&mut self, event: &PresenceEvent, channel: &str) -> Option<String> {
+        match self.mode {
+            PresenceRelayMode::Off => return None,
+            PresenceRelayMode::Compact if self.sent_in_window < self.rate_limit => {
+                self.sent_in_window += 1;
+                return Some(event.describe(channel));
+            }
+            _ => {}
+        }
+        match event {
+            PresenceEvent::Join(_) => self.joined += 1,
+            PresenceEvent::Nick(_, _) => self.renamed += 1,
+            _ => self.left += 1,
+        }
+        None
+    }
+
+    /// Returns a summary of the events counted since the last call, and starts a new window.
+    fn take_summary(&mut self) -> Option<String> {
+        self.sent_in_window = 0;
+        let mut parts = Vec::new();
+        if self.joined > 0 {
+            parts.push(format!("{} joined", self.joined));
+        }
+        if self.left > 0 {
+            parts.push(format!("{} left", self.left));
+        }
+        if self.renamed > 0 {
+            parts.push(format!("{} changed nick", self.renamed));
+        }
+        (self.joined, self.left, self.renamed) = (0, 0, 0);
+        if parts.is_empty() {
+            return None;
+        }
+        let minutes = self.interval.as_secs() / 60;
+        let period = if minutes <= 1 {
+            "minute".to_owned()
+        } else {
+            format!("{minutes} minutes")
+        };
+        Some(format!(
+            "{} on IRC in the last {}",
+            parts.join(", "),
+            period
+        ))
+    }
+}
+
+async fn send_presence(context: &Context, message: &str) {
+    context.mark_someone_talked();
+    context
+        .send_to_discord_channel(
+            &format!("_{}_", Context::escape_discord_markdown(message)),
+            &context.discord_channel,
+        )
+        .await;
+}
+
+pub(crate) async fn relay(context: &Context, event: PresenceEvent) {
+    let line = context
+        .irc_presence
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .record(&event, &context.irc_channel);
+    if let Some(line) = line {
+        send_presence(context, &line).await;
+    }
+}
+
+/// Sends the summary of the events counted so far, if there are any.
+pub(crate) async fn flush(context: &Context) {
+    let summary = context
+        .irc_presence
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take_summary();
+    if let Some(summary) = summary {
+        send_presence(context, &summary).await;
+    }
+}
+
+pub(crate) async fn start(context: Context) {
+    let (mode, interval) = {
+        let presence = context
+            .irc_presence
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        (presence.mode, presence.interval)
+    };
+    if mode == PresenceRelayMode::Off {
+        return;
+    }
+
+    info!("Starting IRC presence summary loop");
+    context.metrics.watch("presence", interval);
+    loop {
+        sleep(interval).await;
+        context.metrics.heartbeat("presence");
+        flush(&context).await;
+    }
+}
@@ -0,0 +1,129 @@
+use crate::api::ScheduleResponse;
+use crate::context::Context;
+use crate::{api, events, storage};
+use chrono::Utc;
+use log::{error, info};
+use serenity::all::{CreateAllowedMentions, CreateMessage};
+use std::collections::HashSet;
+use std::env;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time::sleep;
+
+pub(crate) const STORAGE_NAME: &str = "schedule_reminders";
+
+/// How long before its scheduled start a show going live still counts as that show, as DJs may
+/// start up to half an hour early.
+pub(crate) const EARLY_START_SECONDS: i64 = 30 * 60;
+
+pub(crate) async fn start(context: Context) {
+    let check_interval = env::var("SCHEDULE_CHECK_INTERVAL")
+        .unwrap_or("60".to_owned())
+        .parse()
+        .expect("SCHEDULE_CHECK_INTERVAL must be a number");
+    let reminder_minutes: i64 = env::var("SCHEDULE_REMINDER_MINUTES")
+        .unwrap_or("15".to_owned())
+        .parse()
+        .expect("SCHEDULE_REMINDER_MINUTES must be a number");
+
+    // Shows we already sent a reminder for, keyed by schedule ID and start time. Saved so a restart
+    // doesn't send them again.
+    let mut reminded: HashSet<(u64, u64)> = storage::load(STORAGE_NAME);
+
+    info!("Starting schedule loop");
+    context
+        .metrics
+        .watch("scheduler", Duration::from_secs(check_interval));
+    loop {
+        match api::get_schedule_entries().await {
+            Ok(entries) => {
+                let now = Utc::now().timestamp();
+                let reminder_count = reminded.len();
+                reminded.retain(|(_, start)| *start as i64 > now);
+                if reminded.len() != reminder_count {
+                    storage::save(STORAGE_NAME, &reminded);
+                }
+                if let Err(error) = events::sync(&context, &entries).await {
+                    error!("Error syncing scheduled events: {:?}", error);
+                }
+                for entry in entries {
+                    let minutes_until_start = (entry.start_timestamp as i64 - now) / 60;
+                    if entry.is_now
+                        || !(0..reminder_minutes).contains(&minutes_until_start)
+                        || !reminded.insert((entry.id, entry.start_timestamp))
+                    {
+                        continue;
+                    }
+                    storage::save(STORAGE_NAME, &reminded);
+                    context
+                        .send_message(&format!(
+                            "Starting in {} minutes: {}",
+                            minutes_until_start + 1,
+                            entry.title
+                        ))
+                        .await;
+                }
+            }
+            Err(error) => {
+                error!("Error getting schedule: {:?}", error);
+                context.metrics.api_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        context.metrics.heartbeat("scheduler");
+        sleep(Duration::from_secs(check_interval)).await;
+    }
+}
+
+/// The schedule entry that's on now, or about to start.
+fn scheduled_show(entries: Vec<ScheduleResponse>, now: i64) -> Option<ScheduleResponse> {
+    entries.into_iter().find(|entry| {
+        entry.is_now
+            || (entry.start_timestamp as i64 - EARLY_START_SECONDS..entry.end_timestamp as i64)
+                .contains(&now)
+    })
+}
+
+/// Announces a scheduled show going live, pinging the live role on Discord if one is configured.
+/// Shows that aren't on the schedule aren't announced. Returns whether the show was announced.
+pub(crate) async fn announce_live(context: &Context, dj: &str) -> bool {
+    let show = match api::get_schedule_entries().await {
+        Ok(entries) => scheduled_show(entries, Utc::now().timestamp()),
+        Err(error) => {
+            error!("Error getting schedule: {:?}", error);
+            return false;
+        }
+    };
+    let Some(show) = show else {
+        info!("{} went live outside the schedule, not announcing it", dj);
+        return false;
+    };
+    let message = if show.title.eq_ignore_ascii_case(dj) {
+        format!("{dj} is going LIVE!")
+    } else {
+        format!("{dj} is going LIVE: {}", show.title)
+    };
+
+    context.send_to_irc(&message, None).await;
+
+    let discord_message = Context::escape_discord_markdown(&message);
+    let builder = match context.discord_live_role {
+        Some(role) => CreateMessage::new()
+            .content(format!("<@&{role}> {discord_message}"))
+            .allowed_mentions(CreateAllowedMentions::new().roles(vec![role])),
+        None => CreateMessage::new().content(discord_message),
+    };
+    if let Err(error) = context
+        .discord_channel
+        .send_message(&context.discord_http, builder)
+        .await
+    {
+        error!("Error sending live announcement to Discord: {:?}", error);
+    }
+    true
+}
+
+pub(crate) async fn announce_show_over(context: &Context, dj: &str) {
+    context
+        .send_message(&format!("That's the end of the show, thanks {dj}!"))
+        .await;
+}
@@ -4,6 +4,7 @@ use log::debug;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
 
 pub(crate) mod fingerprinting {
     pub mod algorithm;
@@ -17,15 +18,24 @@ pub use fingerprinting::algorithm::SignatureGenerator;
 pub use fingerprinting::communication::recognize_song_from_signature;
 pub use fingerprinting::signature_format::DecodedSignature;
 
+pub(crate) const STORAGE_NAME: &str = "last_track";
+
 pub(crate) fn get_last_sent_track(context: &Context) -> Option<(chrono::NaiveDateTime, String)> {
-    context.last_track.read().unwrap().clone()
+    context
+        .last_track
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
 }
 
 pub(crate) fn set_last_sent_track(
     context: &Context,
     track: Option<(chrono::NaiveDateTime, String)>,
 ) {
-    let mut last_track = context.last_track.write().unwrap();
+    let mut last_track = context
+        .last_track
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
     *last_track = track;
 }
 
@@ -38,8 +48,21 @@ pub(crate) async fn start(context: Context) {
             continue;
         }
 
+        context
+            .metrics
+            .shazam_attempts
+            .fetch_add(1, Ordering::Relaxed);
         let track = match recognize_from_stream(&input_url).await {
-            Ok(track) => track,
+            Ok(track) => {
+                context
+                    .metrics
+                    .shazam_matches
+                    .fetch_add(1, Ordering::Relaxed);
+                // Recognition only runs during live shows and can fail for a long time on
+                // unreleased tracks, so this task isn't watched.
+                context.metrics.heartbeat("shazam");
+                track
+            }
             Err(e) => {
                 debug!("Error recognizing song: {e}");
                 continue;
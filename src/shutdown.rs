@@ -0,0 +1,67 @@
+use crate::context::Context;
+use crate::{ansi_relay, irc_link, presence, shazam, storage};
+use log::{error, info};
+use std::env;
+use std::sync::atomic::Ordering;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep;
+
+/// Waits for `docker stop`'s SIGTERM, or Ctrl+C when running in a terminal.
+pub(crate) async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Received Ctrl+C, shutting down"),
+    }
+}
+
+/// Saves the state that otherwise only lives in memory.
+pub(crate) fn save_state(context: &Context) {
+    context.save_np_state();
+    storage::save(
+        shazam::STORAGE_NAME,
+        &*context
+            .last_track
+            .read()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+}
+
+/// Stops taking commands, sends what's still queued, leaves IRC with `IRC_QUIT_MESSAGE`, closes
+/// the Discord gateway and saves state. Lines buffered during an IRC outage can't be sent anymore,
+/// so Discord is told they were lost.
+pub(crate) async fn shutdown(context: &Context) {
+    context.shutting_down.store(true, Ordering::Release);
+    ansi_relay::flush(context).await;
+    presence::flush(context).await;
+    irc_link::discard_buffer(context).await;
+
+    let quit_message = env::var("IRC_QUIT_MESSAGE").unwrap_or("Shutting down".to_owned());
+    let quit_result = context
+        .irc_sender
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .send_quit(quit_message);
+    match quit_result {
+        Ok(()) => {
+            // The server closes the connection once everything before the QUIT has been sent.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while context
+                .irc_link
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_connected()
+                && Instant::now() < deadline
+            {
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+        Err(error) => error!("Error quitting IRC: {:?}", error),
+    }
+
+    save_state(context);
+    context.discord_shard_manager.shutdown_all().await;
+    info!("Shutdown complete");
+}
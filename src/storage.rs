@@ -0,0 +1,66 @@
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn get_path(name: &str) -> PathBuf {
+    let data_dir = env::var("DATA_DIR").unwrap_or("data".to_owned());
+    PathBuf::from(data_dir).join(format!("{name}.json"))
+}
+
+/// Makes sure state can be saved to `DATA_DIR`, panicking at startup rather than failing every
+/// save later.
+pub(crate) fn check_writable() {
+    let path = get_path("write_check");
+    let data_dir = path.parent().expect("State files are in DATA_DIR");
+    let result = fs::create_dir_all(data_dir)
+        .and_then(|_| fs::write(&path, "{}"))
+        .and_then(|_| fs::remove_file(&path));
+    if let Err(error) = result {
+        panic!(
+            "DATA_DIR {} must be writable: {}",
+            data_dir.display(),
+            error
+        );
+    }
+}
+
+/// Loads a JSON state file from `DATA_DIR`, falling back to the default value if it doesn't exist
+/// or can't be parsed.
+pub(crate) fn load<T>(name: &str) -> T
+where
+    T: DeserializeOwned + Default,
+{
+    let path = get_path(name);
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return T::default();
+    };
+    serde_json::from_str(&contents).unwrap_or_else(|error| {
+        error!("Could not parse {}: {:?}", path.display(), error);
+        T::default()
+    })
+}
+
+pub(crate) fn save<T>(name: &str, value: &T)
+where
+    T: Serialize,
+{
+    let path = get_path(name);
+    if let Some(parent) = path.parent() {
+        if let Err(error) = fs::create_dir_all(parent) {
+            error!("Could not create {}: {:?}", parent.display(), error);
+            return;
+        }
+    }
+    // Write to a temporary file first so a crash can't leave a half-written state file behind.
+    let temp_path = path.with_extension("json.tmp");
+    let result = serde_json::to_string_pretty(value)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(fs::write(&temp_path, contents)?))
+        .and_then(|_| Ok(fs::rename(&temp_path, &path)?));
+    if let Err(error) = result {
+        error!("Could not save {}: {:?}", path.display(), error);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::context::Context;
+use log::{error, info};
+use serenity::all::ChannelId;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::env;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// How many crashes of a single task are tolerated before giving up and letting Docker restart the
+/// whole process.
+pub(crate) struct RestartPolicy {
+    max_restarts: usize,
+    window: Duration,
+    admin_channel: Option<ChannelId>,
+}
+
+impl RestartPolicy {
+    pub(crate) fn from_env() -> Self {
+        RestartPolicy {
+            max_restarts: env::var("SUPERVISOR_MAX_RESTARTS")
+                .unwrap_or("5".to_owned())
+                .parse()
+                .expect("SUPERVISOR_MAX_RESTARTS must be a number"),
+            window: Duration::from_secs(
+                env::var("SUPERVISOR_RESTART_WINDOW")
+                    .unwrap_or("600".to_owned())
+                    .parse()
+                    .expect("SUPERVISOR_RESTART_WINDOW must be a number"),
+            ),
+            admin_channel: env::var("ADMIN_DISCORD_CHANNEL_ID")
+                .ok()
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    s.parse()
+                        .expect("ADMIN_DISCORD_CHANNEL_ID must be a number")
+                }),
+        }
+    }
+}
+
+/// The recent crashes of a single task, which decide when it restarts.
+struct Crashes {
+    times: VecDeque<Instant>,
+    delay: Duration,
+}
+
+impl Crashes {
+    fn new() -> Self {
+        Crashes {
+            times: VecDeque::new(),
+            delay: MIN_RESTART_DELAY,
+        }
+    }
+
+    /// Records a crash at `now` of a run that began at `started`. Returns how long to wait before
+    /// restarting, or `None` if the task crashed too often within the policy's window.
+    fn record(
+        &mut self,
+        policy: &RestartPolicy,
+        started: Instant,
+        now: Instant,
+    ) -> Option<Duration> {
+        // A task that ran for a while before crashing starts over with a short delay.
+        if now - started > policy.window {
+            self.delay = MIN_RESTART_DELAY;
+        }
+        self.times.retain(|crash| now - *crash < policy.window);
+        self.times.push_back(now);
+        if self.times.len() > policy.max_restarts {
+            return None;
+        }
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_RESTART_DELAY);
+        Some(delay)
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+async fn report(context: &Context, message: &str) {
+    error!("{}", message);
+    if let Some(channel) = &context.restart_policy.admin_channel {
+        context.send_to_discord_channel(message, channel).await;
+    }
+}
+
+/// Runs a background task, restarting it with backoff whenever it panics. A task that returns is
+/// done and isn't restarted. Too many crashes in a row end the process. Shared state in `Context`
+/// is locked with `unwrap_or_else(PoisonError::into_inner)`, so a task that panics while holding a
+/// lock doesn't take the other tasks down with it.
+pub(crate) async fn supervise<F, Fut>(context: Context, name: &'static str, task: F)
+where
+    F: Fn(Context) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let policy = &context.restart_policy;
+    let mut crashes = Crashes::new();
+    loop {
+        let started = Instant::now();
+        let panic = match tokio::spawn(task(context.clone())).await {
+            Ok(()) => {
+                info!("Task {} finished", name);
+                return;
+            }
+            Err(error) if error.is_panic() => error.into_panic(),
+            Err(error) => {
+                error!("Task {} was cancelled: {:?}", name, error);
+                return;
+            }
+        };
+        let message = panic_message(&*panic).to_owned();
+        let Some(delay) = crashes.record(policy, started, Instant::now()) else {
+            report(
+                &context,
+                &format!(
+                    "⚠️ Task {} crashed {} times in {} minutes, last with: {}. Exiting.",
+                    name,
+                    crashes.times.len(),
+                    policy.window.as_secs() / 60,
+                    message
+                ),
+            )
+            .await;
+            std::process::exit(1);
+        };
+        report(
+            &context,
+            &format!(
+                "⚠️ Task {} crashed: {}. Restarting in {} seconds.",
+                name,
+                message,
+                delay.as_secs()
+            ),
+        )
+        .await;
+        sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crashes, RestartPolicy, MAX_RESTART_DELAY, MIN_RESTART_DELAY};
+    use std::time::{Duration, Instant};
+
+    fn policy() -> RestartPolicy {
+        RestartPolicy {
+            max_restarts: 3,
+            window: Duration::from_secs(600),
+            admin_channel: None,
+        }
+    }
+
+    #[test]
+    fn doubles_the_delay_between_quick_crashes() {
+        let policy = RestartPolicy {
+            max_restarts: 10,
+            ..policy()
+        };
+        let mut crashes = Crashes::new();
+        let now = Instant::now();
+        let delays = (0..8)
+            .map(|_| crashes.record(&policy, now, now).unwrap().as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(MIN_RESTART_DELAY.as_secs(), delays[0]);
+        assert_eq!(MAX_RESTART_DELAY.as_secs(), delays[7]);
+    }
+
+    #[test]
+    fn gives_up_after_too_many_crashes_in_the_window() {
+        let policy = policy();
+        let mut crashes = Crashes::new();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(crashes.record(&policy, now, now).is_some());
+        }
+        assert_eq!(crashes.record(&policy, now, now), None);
+    }
+
+    #[test]
+    fn forgets_crashes_outside_the_window() {
+        let policy = policy();
+        let mut crashes = Crashes::new();
+        let start = Instant::now();
+        for _ in 0..3 {
+            crashes.record(&policy, start, start);
+        }
+        let later = start + Duration::from_secs(601);
+        assert!(crashes.record(&policy, later, later).is_some());
+        assert_eq!(crashes.times.len(), 1);
+    }
+
+    #[test]
+    fn resets_the_delay_after_a_long_run() {
+        let policy = policy();
+        let mut crashes = Crashes::new();
+        let start = Instant::now();
+        crashes.record(&policy, start, start);
+        crashes.record(&policy, start, start);
+        let later = start + Duration::from_secs(601);
+        assert_eq!(
+            crashes.record(&policy, start, later),
+            Some(MIN_RESTART_DELAY)
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use crate::context::Context;
+use crate::storage;
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::sync::PoisonError;
+
+pub(crate) const STORAGE_NAME: &str = "timezones";
+
+/// Timezone names keyed by `discord:<user ID>` for Discord users, `irc:<account>` for IRC users
+/// logged in to services, and the lowercase nickname for other IRC users.
+pub(crate) type Timezones = HashMap<String, String>;
+
+pub(crate) fn get_timezone(context: &Context, nickname: &str) -> Option<Tz> {
+    context
+        .timezones
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&nickname.to_lowercase())
+        .and_then(|timezone| timezone.parse().ok())
+}
+
+pub(crate) fn set_timezone(context: &Context, nickname: &str, timezone: Option<Tz>) {
+    let mut timezones = context
+        .timezones
+        .write()
+        .unwrap_or_else(PoisonError::into_inner);
+    match timezone {
+        Some(timezone) => timezones.insert(nickname.to_lowercase(), timezone.name().to_owned()),
+        None => timezones.remove(&nickname.to_lowercase()),
+    };
+    storage::save(STORAGE_NAME, &*timezones);
+}
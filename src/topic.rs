@@ -0,0 +1,129 @@
+use crate::context::Context;
+use crate::formatting;
+use log::{error, info};
+use serenity::all::EditChannel;
+use std::collections::VecDeque;
+use std::sync::PoisonError;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Discord only allows two topic edits per channel every ten minutes.
+const DISCORD_TOPIC_EDITS: usize = 2;
+const DISCORD_TOPIC_WINDOW: Duration = Duration::from_secs(600);
+const DISCORD_TOPIC_MAX_LENGTH: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct TopicState {
+    /// Set when an IRC op changed the topic by hand, cleared on the next track change.
+    manual: bool,
+    discord_topic: Option<String>,
+    pending_discord_topic: Option<String>,
+    discord_edits: VecDeque<Instant>,
+}
+
+/// Sets the now playing topic on both networks, unless an op set one by hand since the last track
+/// change.
+pub(crate) async fn set_topic(context: &Context, topic: String, track_changed: bool) {
+    let manual = {
+        let mut state = context
+            .topic_state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if track_changed {
+            state.manual = false;
+        }
+        state.manual
+    };
+    if manual {
+        return;
+    }
+    if let Err(error) = context.set_irc_topic(topic.clone()).await {
+        error!("Error setting IRC topic: {:?}", error);
+    }
+    queue_discord_topic(context, topic).await;
+}
+
+/// Mirrors a topic an op set on IRC to Discord.
+pub(crate) async fn mirror_irc_topic(context: &Context, topic: &str) {
+    context
+        .topic_state
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .manual = true;
+    queue_discord_topic(context, topic.to_owned()).await;
+}
+
+/// Queues an IRC topic for Discord, without the IRC formatting codes.
+async fn queue_discord_topic(context: &Context, topic: String) {
+    if !context.discord_topic_sync {
+        return;
+    }
+    let topic = formatting::strip_irc(&topic)
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(DISCORD_TOPIC_MAX_LENGTH)
+        .collect();
+    context
+        .topic_state
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .pending_discord_topic = Some(topic);
+    flush_discord_topic(context).await;
+}
+
+/// Applies the pending Discord topic if the rate limit allows it.
+async fn flush_discord_topic(context: &Context) {
+    let topic = {
+        let mut state = context
+            .topic_state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        while state
+            .discord_edits
+            .front()
+            .is_some_and(|edit| now.duration_since(*edit) > DISCORD_TOPIC_WINDOW)
+        {
+            state.discord_edits.pop_front();
+        }
+        if state.pending_discord_topic.is_none()
+            || state.pending_discord_topic == state.discord_topic
+        {
+            state.pending_discord_topic = None;
+            return;
+        }
+        if state.discord_edits.len() >= DISCORD_TOPIC_EDITS {
+            return;
+        }
+        state.discord_edits.push_back(now);
+        state.pending_discord_topic.take().unwrap()
+    };
+
+    match context
+        .discord_channel
+        .edit(&context.discord_http, EditChannel::new().topic(&topic))
+        .await
+    {
+        Ok(_) => {
+            context
+                .topic_state
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .discord_topic = Some(topic)
+        }
+        Err(error) => error!("Error setting Discord topic: {:?}", error),
+    }
+}
+
+pub(crate) async fn start(context: Context) {
+    if !context.discord_topic_sync {
+        return;
+    }
+    info!("Starting Discord topic loop");
+    context.metrics.watch("topic", Duration::from_secs(30));
+    loop {
+        sleep(Duration::from_secs(30)).await;
+        flush_discord_topic(&context).await;
+        context.metrics.heartbeat("topic");
+    }
+}